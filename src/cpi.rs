@@ -0,0 +1,509 @@
+// Cross-Program Invocation (CPI) helpers for programs that depend on this crate with
+// the `no-entrypoint` feature, e.g., a vault program opening and settling escrows on
+// behalf of its users.
+// NOTE Each helper builds the instruction with the builders in instruction.rs and
+// calls invoke_signed(). Pass the seeds of every PDA of YOUR program that needs to sign
+// (e.g., a PDA acting as the escrow's initializer or taker), or `&[]` when the signers
+// already signed the outer transaction. With no seeds, invoke_signed() is just invoke().
+// NOTE Just like with spl_token CPIs, every account of the instruction (including the
+// escrow program itself) must be passed in as an AccountInfo.
+use solana_program::{
+    account_info::AccountInfo,
+    entrypoint::ProgramResult,
+    program::invoke_signed,
+    pubkey::Pubkey,
+};
+
+use crate::instruction::{self, NativeSide};
+
+/// Accounts of an `InitEscrow` CPI, see `EscrowInstruction::InitEscrow`
+pub struct InitEscrow<'a> {
+    pub initializer: AccountInfo<'a>,
+    /// Ignored when offering native SOL (pass the initializer)
+    pub initializers_sending_token_account: AccountInfo<'a>,
+    pub vault: AccountInfo<'a>,
+    pub initializers_token_to_receive_account: AccountInfo<'a>,
+    pub escrow: AccountInfo<'a>,
+    pub rent_sysvar: AccountInfo<'a>,
+    /// The offered token's program (SPL Token when offering native SOL)
+    pub token_program: AccountInfo<'a>,
+    pub system_program: AccountInfo<'a>,
+    pub config: AccountInfo<'a>,
+    /// Not needed when offering native SOL
+    pub offered_mint: Option<AccountInfo<'a>>,
+    /// The offered NFT's metadata account, only needed when checking its collection
+    pub offered_metadata: Option<AccountInfo<'a>>,
+    /// Not needed when requesting native SOL
+    pub requested_mint: Option<AccountInfo<'a>>,
+    /// Empty unless it's a basket trade
+    pub basket_offered: Vec<InitOfferedLeg<'a>>,
+    /// Empty unless it's a basket trade
+    pub basket_requested: Vec<InitRequestedLeg<'a>>,
+}
+
+/// An extra token offered in a basket `InitEscrow` CPI
+pub struct InitOfferedLeg<'a> {
+    pub initializers_sending_token_account: AccountInfo<'a>,
+    pub vault: AccountInfo<'a>,
+    pub mint: AccountInfo<'a>,
+    pub amount: u64,
+}
+
+/// An extra token requested in a basket `InitEscrow` CPI
+pub struct InitRequestedLeg<'a> {
+    pub initializers_token_to_receive_account: AccountInfo<'a>,
+    pub mint: AccountInfo<'a>,
+    pub amount: u64,
+}
+
+/// The accounts of an extra offered token in a basket `Exchange`, `Cancel` or
+/// `RefundExpired` CPI
+pub struct OfferedLeg<'a> {
+    pub vault: AccountInfo<'a>,
+    pub token_account: AccountInfo<'a>,
+    pub mint: AccountInfo<'a>,
+}
+
+/// The accounts of an extra requested token in a basket `Exchange` CPI
+pub struct RequestedLeg<'a> {
+    pub takers_sending_token_account: AccountInfo<'a>,
+    pub initializers_token_to_receive_account: AccountInfo<'a>,
+    pub fee_recipient: AccountInfo<'a>,
+    pub mint: AccountInfo<'a>,
+}
+
+/// Opens an escrow. The initializer (or the PDA whose seeds are passed in) pays for
+/// the escrow account and vault, and its offered tokens are moved into the vault.
+#[allow(clippy::too_many_arguments)]
+pub fn init_escrow<'a>(
+    escrow_program: &AccountInfo<'a>,
+    accounts: InitEscrow<'a>,
+    amount: u64,
+    offered_amount: u64,
+    expires_at: Option<i64>,
+    native_side: NativeSide,
+    nonce: u64,
+    allowed_taker: Option<Pubkey>,
+    taker_merkle_root: Option<[u8; 32]>,
+    offered_nft: bool,
+    offered_collection: Option<Pubkey>,
+    signer_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    let offered_mint = accounts
+        .offered_mint
+        .as_ref()
+        .map(|offered_mint| *offered_mint.key)
+        .unwrap_or_default();
+    let requested_mint = accounts
+        .requested_mint
+        .as_ref()
+        .map(|requested_mint| *requested_mint.key)
+        .unwrap_or_default();
+    let ix = instruction::init_escrow(
+        escrow_program.key,
+        accounts.token_program.key,
+        accounts.initializer.key,
+        accounts.initializers_sending_token_account.key,
+        accounts.initializers_token_to_receive_account.key,
+        &offered_mint,
+        &requested_mint,
+        amount,
+        offered_amount,
+        expires_at,
+        native_side,
+        nonce,
+        allowed_taker,
+        taker_merkle_root,
+        &accounts
+            .basket_offered
+            .iter()
+            .map(|leg| instruction::InitOfferedLeg {
+                initializers_sending_token_account: *leg.initializers_sending_token_account.key,
+                mint: *leg.mint.key,
+                amount: leg.amount,
+            })
+            .collect::<Vec<_>>(),
+        &accounts
+            .basket_requested
+            .iter()
+            .map(|leg| instruction::InitRequestedLeg {
+                initializers_token_to_receive_account: *leg.initializers_token_to_receive_account.key,
+                mint: *leg.mint.key,
+                amount: leg.amount,
+            })
+            .collect::<Vec<_>>(),
+        offered_nft,
+        offered_collection,
+    )?;
+
+    let mut account_infos = vec![
+        accounts.initializer,
+        accounts.initializers_sending_token_account,
+        accounts.vault,
+        accounts.initializers_token_to_receive_account,
+        accounts.escrow,
+        accounts.rent_sysvar,
+        accounts.token_program,
+        accounts.system_program,
+        accounts.config,
+    ];
+    if let Some(offered_mint) = accounts.offered_mint {
+        account_infos.push(offered_mint);
+    }
+    if let Some(offered_metadata) = accounts.offered_metadata {
+        account_infos.push(offered_metadata);
+    }
+    if let Some(requested_mint) = accounts.requested_mint {
+        account_infos.push(requested_mint);
+    }
+    for leg in accounts.basket_offered {
+        account_infos.push(leg.initializers_sending_token_account);
+        account_infos.push(leg.vault);
+        account_infos.push(leg.mint);
+    }
+    for leg in accounts.basket_requested {
+        account_infos.push(leg.initializers_token_to_receive_account);
+        account_infos.push(leg.mint);
+    }
+    account_infos.push(escrow_program.clone());
+
+    invoke_signed(&ix, &account_infos, signer_seeds)
+}
+
+/// Accounts of an `Exchange` CPI, see `EscrowInstruction::Exchange`
+pub struct Exchange<'a> {
+    pub taker: AccountInfo<'a>,
+    pub takers_sending_token_account: AccountInfo<'a>,
+    pub takers_receiving_token_account: AccountInfo<'a>,
+    /// The vault saved in the escrow account (`Escrow::temp_token_account_pubkey`)
+    pub vault: AccountInfo<'a>,
+    pub initializer: AccountInfo<'a>,
+    pub initializers_token_to_receive_account: AccountInfo<'a>,
+    pub escrow: AccountInfo<'a>,
+    /// The offered token's program
+    pub token_program: AccountInfo<'a>,
+    pub pda: AccountInfo<'a>,
+    pub config: AccountInfo<'a>,
+    pub fee_recipient: AccountInfo<'a>,
+    pub system_program: AccountInfo<'a>,
+    pub requested_token_program: AccountInfo<'a>,
+    /// Not needed when native SOL was offered
+    pub offered_mint: Option<AccountInfo<'a>>,
+    /// Not needed when native SOL was requested
+    pub requested_mint: Option<AccountInfo<'a>>,
+    /// Only when the taker receives in their associated token accounts
+    pub associated_token_program: Option<AccountInfo<'a>>,
+    /// Empty unless it's a basket trade
+    pub basket_offered: Vec<OfferedLeg<'a>>,
+    /// Empty unless it's a basket trade
+    pub basket_requested: Vec<RequestedLeg<'a>>,
+}
+
+/// Takes `amount` of the offered token out of an escrow
+pub fn exchange<'a>(
+    escrow_program: &AccountInfo<'a>,
+    accounts: Exchange<'a>,
+    amount: u64,
+    proof: Vec<[u8; 32]>,
+    signer_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    let ix = instruction::exchange(
+        escrow_program.key,
+        accounts.token_program.key,
+        accounts.requested_token_program.key,
+        accounts.taker.key,
+        accounts.takers_sending_token_account.key,
+        accounts.takers_receiving_token_account.key,
+        accounts.initializer.key,
+        accounts.initializers_token_to_receive_account.key,
+        accounts.escrow.key,
+        accounts.vault.key,
+        accounts.fee_recipient.key,
+        accounts.offered_mint.as_ref().map(|offered_mint| offered_mint.key),
+        accounts.requested_mint.as_ref().map(|requested_mint| requested_mint.key),
+        amount,
+        proof,
+        &offered_legs(&accounts.basket_offered),
+        &accounts
+            .basket_requested
+            .iter()
+            .map(|leg| instruction::RequestedLeg {
+                takers_sending_token_account: *leg.takers_sending_token_account.key,
+                initializers_token_to_receive_account: *leg.initializers_token_to_receive_account.key,
+                fee_recipient: *leg.fee_recipient.key,
+                mint: *leg.mint.key,
+            })
+            .collect::<Vec<_>>(),
+        accounts.associated_token_program.is_some(),
+    )?;
+
+    let mut account_infos = vec![
+        accounts.taker,
+        accounts.takers_sending_token_account,
+        accounts.takers_receiving_token_account,
+        accounts.vault,
+        accounts.initializer,
+        accounts.initializers_token_to_receive_account,
+        accounts.escrow,
+        accounts.token_program,
+        accounts.pda,
+        accounts.config,
+        accounts.fee_recipient,
+        accounts.system_program,
+        accounts.requested_token_program,
+    ];
+    if let Some(offered_mint) = accounts.offered_mint {
+        account_infos.push(offered_mint);
+    }
+    if let Some(requested_mint) = accounts.requested_mint {
+        account_infos.push(requested_mint);
+    }
+    if let Some(associated_token_program) = accounts.associated_token_program {
+        account_infos.push(associated_token_program);
+    }
+    push_offered_legs(&mut account_infos, accounts.basket_offered);
+    for leg in accounts.basket_requested {
+        account_infos.push(leg.takers_sending_token_account);
+        account_infos.push(leg.initializers_token_to_receive_account);
+        account_infos.push(leg.fee_recipient);
+        account_infos.push(leg.mint);
+    }
+    account_infos.push(escrow_program.clone());
+
+    invoke_signed(&ix, &account_infos, signer_seeds)
+}
+
+/// Accounts of a `Cancel` or `RefundExpired` CPI
+pub struct Refund<'a> {
+    pub initializer: AccountInfo<'a>,
+    /// The vault saved in the escrow account (`Escrow::temp_token_account_pubkey`)
+    pub vault: AccountInfo<'a>,
+    /// Ignored when native SOL was offered
+    pub initializers_refund_token_account: AccountInfo<'a>,
+    pub escrow: AccountInfo<'a>,
+    /// The offered token's program
+    pub token_program: AccountInfo<'a>,
+    pub pda: AccountInfo<'a>,
+    /// Not needed when native SOL was offered
+    pub offered_mint: Option<AccountInfo<'a>>,
+    /// Only when refunding into the initializer's associated token accounts
+    pub associated_token_accounts: Option<RefundIntoAssociatedTokenAccounts<'a>>,
+    /// Empty unless it's a basket trade
+    pub basket_offered: Vec<OfferedLeg<'a>>,
+}
+
+/// The extra accounts of a `Cancel` or `RefundExpired` CPI refunding into the
+/// initializer's associated token accounts
+pub struct RefundIntoAssociatedTokenAccounts<'a> {
+    /// Pays for the associated token accounts. Ignored by `Cancel`, where the initializer pays
+    pub payer: AccountInfo<'a>,
+    pub system_program: AccountInfo<'a>,
+    pub associated_token_program: AccountInfo<'a>,
+}
+
+/// Cancels an escrow. The initializer (or the PDA whose seeds are passed in) must sign.
+pub fn cancel<'a>(
+    escrow_program: &AccountInfo<'a>,
+    accounts: Refund<'a>,
+    signer_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    let ix = instruction::cancel(
+        escrow_program.key,
+        accounts.token_program.key,
+        accounts.initializer.key,
+        accounts.initializers_refund_token_account.key,
+        accounts.escrow.key,
+        accounts.vault.key,
+        accounts.offered_mint.as_ref().map(|offered_mint| offered_mint.key),
+        &offered_legs(&accounts.basket_offered),
+        accounts.associated_token_accounts.is_some(),
+    )?;
+
+    let mut account_infos = vec![
+        accounts.initializer,
+        accounts.vault,
+        accounts.initializers_refund_token_account,
+        accounts.escrow,
+        accounts.token_program,
+        accounts.pda,
+    ];
+    if let Some(offered_mint) = accounts.offered_mint {
+        account_infos.push(offered_mint);
+    }
+    if let Some(associated_token_accounts) = accounts.associated_token_accounts {
+        account_infos.push(associated_token_accounts.system_program);
+        account_infos.push(associated_token_accounts.associated_token_program);
+    }
+    push_offered_legs(&mut account_infos, accounts.basket_offered);
+    account_infos.push(escrow_program.clone());
+
+    invoke_signed(&ix, &account_infos, signer_seeds)
+}
+
+/// Refunds an expired escrow to its initializer. Nobody needs to sign, except the payer
+/// of the initializer's associated token accounts (if any).
+pub fn refund_expired<'a>(
+    escrow_program: &AccountInfo<'a>,
+    accounts: Refund<'a>,
+    signer_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    let ix = instruction::refund_expired(
+        escrow_program.key,
+        accounts.token_program.key,
+        accounts.initializers_refund_token_account.key,
+        accounts.initializer.key,
+        accounts.escrow.key,
+        accounts.vault.key,
+        accounts.offered_mint.as_ref().map(|offered_mint| offered_mint.key),
+        &offered_legs(&accounts.basket_offered),
+        accounts
+            .associated_token_accounts
+            .as_ref()
+            .map(|associated_token_accounts| associated_token_accounts.payer.key),
+    )?;
+
+    let mut account_infos = vec![
+        accounts.vault,
+        accounts.initializers_refund_token_account,
+        accounts.initializer,
+        accounts.escrow,
+        accounts.token_program,
+        accounts.pda,
+    ];
+    if let Some(offered_mint) = accounts.offered_mint {
+        account_infos.push(offered_mint);
+    }
+    if let Some(associated_token_accounts) = accounts.associated_token_accounts {
+        account_infos.push(associated_token_accounts.payer);
+        account_infos.push(associated_token_accounts.system_program);
+        account_infos.push(associated_token_accounts.associated_token_program);
+    }
+    push_offered_legs(&mut account_infos, accounts.basket_offered);
+    account_infos.push(escrow_program.clone());
+
+    invoke_signed(&ix, &account_infos, signer_seeds)
+}
+
+/// Creates the escrow program's config account with the payer as its admin. The payer
+/// must be the escrow program's upgrade authority.
+#[allow(clippy::too_many_arguments)]
+pub fn init_config<'a>(
+    escrow_program: &AccountInfo<'a>,
+    admin: AccountInfo<'a>,
+    config: AccountInfo<'a>,
+    system_program: AccountInfo<'a>,
+    program_data: AccountInfo<'a>,
+    fee_bps: u16,
+    treasury: &Pubkey,
+    signer_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    let ix = instruction::init_config(escrow_program.key, admin.key, fee_bps, treasury)?;
+    invoke_signed(
+        &ix,
+        &[admin, config, system_program, program_data, escrow_program.clone()],
+        signer_seeds,
+    )
+}
+
+/// Changes the escrow program's fee settings
+pub fn update_config<'a>(
+    escrow_program: &AccountInfo<'a>,
+    admin: AccountInfo<'a>,
+    config: AccountInfo<'a>,
+    fee_bps: u16,
+    treasury: &Pubkey,
+    signer_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    let ix = instruction::update_config(escrow_program.key, admin.key, fee_bps, treasury)?;
+    invoke_signed(&ix, &[admin, config, escrow_program.clone()], signer_seeds)
+}
+
+/// Hands the escrow program's config over to a new admin
+pub fn transfer_admin<'a>(
+    escrow_program: &AccountInfo<'a>,
+    admin: AccountInfo<'a>,
+    config: AccountInfo<'a>,
+    new_admin: &Pubkey,
+    signer_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    let ix = instruction::transfer_admin(escrow_program.key, admin.key, new_admin)?;
+    invoke_signed(&ix, &[admin, config, escrow_program.clone()], signer_seeds)
+}
+
+/// Pauses or unpauses the escrow program
+pub fn set_paused<'a>(
+    escrow_program: &AccountInfo<'a>,
+    admin: AccountInfo<'a>,
+    config: AccountInfo<'a>,
+    paused: bool,
+    signer_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    let ix = instruction::set_paused(escrow_program.key, admin.key, paused)?;
+    invoke_signed(&ix, &[admin, config, escrow_program.clone()], signer_seeds)
+}
+
+/// Accounts of a `MigrateEscrow` CPI, see `EscrowInstruction::MigrateEscrow`
+pub struct MigrateEscrow<'a> {
+    pub payer: AccountInfo<'a>,
+    pub escrow: AccountInfo<'a>,
+    pub system_program: AccountInfo<'a>,
+    pub vault: AccountInfo<'a>,
+    pub initializers_token_to_receive_account: AccountInfo<'a>,
+    pub legacy_pda: AccountInfo<'a>,
+    pub token_program: AccountInfo<'a>,
+    pub offered_mint: AccountInfo<'a>,
+    pub requested_mint: AccountInfo<'a>,
+}
+
+/// Upgrades a legacy escrow account to the latest layout
+pub fn migrate_escrow<'a>(
+    escrow_program: &AccountInfo<'a>,
+    accounts: MigrateEscrow<'a>,
+    signer_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    let ix = instruction::migrate_escrow(
+        escrow_program.key,
+        accounts.payer.key,
+        accounts.escrow.key,
+        accounts.vault.key,
+        accounts.initializers_token_to_receive_account.key,
+        accounts.offered_mint.key,
+        accounts.requested_mint.key,
+    )?;
+
+    invoke_signed(
+        &ix,
+        &[
+            accounts.payer,
+            accounts.escrow,
+            accounts.system_program,
+            accounts.vault,
+            accounts.initializers_token_to_receive_account,
+            accounts.legacy_pda,
+            accounts.token_program,
+            accounts.offered_mint,
+            accounts.requested_mint,
+            escrow_program.clone(),
+        ],
+        signer_seeds,
+    )
+}
+
+// The keys of a basket escrow's extra offered tokens, for the instruction builders
+fn offered_legs(legs: &[OfferedLeg]) -> Vec<instruction::OfferedLeg> {
+    legs.iter()
+        .map(|leg| instruction::OfferedLeg {
+            vault: *leg.vault.key,
+            token_account: *leg.token_account.key,
+            mint: *leg.mint.key,
+        })
+        .collect()
+}
+
+fn push_offered_legs<'a>(account_infos: &mut Vec<AccountInfo<'a>>, legs: Vec<OfferedLeg<'a>>) {
+    for leg in legs {
+        account_infos.push(leg.vault);
+        account_infos.push(leg.token_account);
+        account_infos.push(leg.mint);
+    }
+}
@@ -20,9 +20,9 @@ fn process_instruction(
     );
 
     // Now let's bring in our Processor to perform the magic!
-    Processor::process(program_id, accounts, instruction_data);
-
-    Ok(())
+    // NOTE Return the processor's Result so a failing check actually fails the
+    // transaction instead of being silently dropped.
+    Processor::process(program_id, accounts, instruction_data)
 }
 
 // #[cfg(test)]
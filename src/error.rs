@@ -22,6 +22,114 @@ pub enum EscrowError {
     /// Amount Overflow
     #[error("Amount Overflow")]
     AmountOverflow,
+    /// Escrow Expired
+    #[error("Escrow Expired")]
+    EscrowExpired,
+    /// Escrow Not Expired
+    #[error("Escrow Not Expired")]
+    EscrowNotExpired,
+    /// Offered Mint Mismatch
+    #[error("Offered Mint Mismatch")]
+    OfferedMintMismatch,
+    /// Requested Mint Mismatch
+    #[error("Requested Mint Mismatch")]
+    RequestedMintMismatch,
+    /// Account Not Signer
+    #[error("Account Not Signer")]
+    AccountNotSigner,
+    /// Account Not Writable
+    #[error("Account Not Writable")]
+    AccountNotWritable,
+    /// Invalid Account Owner
+    #[error("Invalid Account Owner")]
+    InvalidAccountOwner,
+    /// Invalid Program Account
+    #[error("Invalid Program Account")]
+    InvalidProgramAccount,
+    /// Invalid Escrow Address
+    #[error("Invalid Escrow Address")]
+    InvalidEscrowAddress,
+    /// Invalid Vault Address
+    #[error("Invalid Vault Address")]
+    InvalidVaultAddress,
+    /// Invalid PDA Account
+    #[error("Invalid PDA Account")]
+    InvalidPdaAccount,
+    /// Initializer Mismatch
+    #[error("Initializer Mismatch")]
+    InitializerMismatch,
+    /// Receive Account Mismatch
+    #[error("Receive Account Mismatch")]
+    ReceiveAccountMismatch,
+    /// Taker Account Mismatch
+    #[error("Taker Account Mismatch")]
+    TakerAccountMismatch,
+    /// Invalid Refund Account
+    #[error("Invalid Refund Account")]
+    InvalidRefundAccount,
+    /// Invalid Fee
+    #[error("Invalid Fee")]
+    InvalidFee,
+    /// Invalid Config Address
+    #[error("Invalid Config Address")]
+    InvalidConfigAddress,
+    /// Fee Recipient Mismatch
+    #[error("Fee Recipient Mismatch")]
+    FeeRecipientMismatch,
+    /// Unauthorized Admin
+    #[error("Unauthorized Admin")]
+    UnauthorizedAdmin,
+    /// Program Paused
+    #[error("Program Paused")]
+    ProgramPaused,
+    /// Unauthorized Taker
+    #[error("Unauthorized Taker")]
+    UnauthorizedTaker,
+    /// Invalid Merkle Proof
+    #[error("Invalid Merkle Proof")]
+    InvalidMerkleProof,
+    /// Escrow Needs Migration
+    #[error("Escrow Needs Migration")]
+    EscrowNeedsMigration,
+    /// Escrow Already Migrated
+    #[error("Escrow Already Migrated")]
+    EscrowAlreadyMigrated,
+    /// Invalid Account Type
+    #[error("Invalid Account Type")]
+    InvalidAccountType,
+    /// Invalid Basket
+    #[error("Invalid Basket")]
+    InvalidBasket,
+    /// Basket Partial Fill
+    #[error("Basket Partial Fill")]
+    BasketPartialFill,
+    /// Invalid NFT
+    #[error("Invalid NFT")]
+    InvalidNft,
+    /// Invalid Metadata Account
+    #[error("Invalid Metadata Account")]
+    InvalidMetadataAccount,
+    /// Collection Mismatch
+    #[error("Collection Mismatch")]
+    CollectionMismatch,
+    /// Received Amount Too Low
+    #[error("Received Amount Too Low")]
+    ReceivedAmountTooLow,
+    /// Invalid Associated Token Account
+    #[error("Invalid Associated Token Account")]
+    InvalidAssociatedTokenAccount,
+    /// Invalid Program Data Account
+    #[error("Invalid Program Data Account")]
+    InvalidProgramDataAccount,
+    /// Not Upgrade Authority
+    #[error("Not Upgrade Authority")]
+    NotUpgradeAuthority,
+    /// Expiry In Past
+    #[error("Expiry In Past")]
+    ExpiryInPast,
+    /// Unsupported Mint Extension
+    #[error("Unsupported Mint Extension")]
+    UnsupportedMintExtension,
 }
 
 // Let's implement a way to turn an EscrowError into a ProgramError
@@ -1,80 +1,1175 @@
 // Defines the API of the program
 // NOTE This module is responsible for decoding instruction_data.
-use std::convert::TryInto;
-use solana_program::program_error::ProgramError;
+// NOTE Instructions are (de)serialized with Borsh, just like our state in state.rs, and
+// derive BorshSchema as well so clients in other languages can generate the layout.
+use borsh::{BorshDeserialize, BorshSchema, BorshSerialize};
+use std::mem::size_of;
+use solana_program::{
+    bpf_loader_upgradeable,
+    instruction::{AccountMeta, Instruction},
+    program_error::ProgramError,
+    pubkey::Pubkey,
+    system_program,
+    sysvar,
+};
 
-use crate::error::EscrowError::InvalidInstruction;
+use crate::{
+    error::EscrowError::InvalidInstruction, metadata::find_metadata_address,
+    token::{spl_associated_token_account, spl_token_2022},
+};
+
+/// Which side of a trade (if any) is paid in native SOL instead of an SPL token
+#[derive(BorshSerialize, BorshDeserialize, BorshSchema, Clone, Copy, Debug, PartialEq)]
+pub enum NativeSide {
+    /// Both sides of the trade are SPL tokens
+    None,
+    /// The initializer offers native SOL, held by the escrow's vault.
+    /// `InitEscrow`'s offered_amount is then in lamports
+    Offered,
+    /// The initializer asks for native SOL. `InitEscrow`'s amount is then in lamports
+    Requested,
+}
 
 
+// NOTE The BorshSchema derive describes each variant with a struct of the variant's fields
+// that's never built, and copies the fields (with their attributes) into it. Hence every
+// field allows dead_code, or rustc would flag the copies as unread.
+#[derive(BorshSerialize, BorshDeserialize, BorshSchema, Clone, Debug, PartialEq)]
 pub enum EscrowInstruction {
 
-    /// Starts the trade by creating and populating an escrow account and 
-    /// transferring ownership of the given temp token account to the PDA
+    /// Starts the trade by creating and populating an escrow account at the PDA
+    /// `[b"escrow", initializer, nonce]`, creating the
+    /// escrow's vault token account and depositing the offered tokens into it.
+    /// The vault lives at the PDA `[b"vault", escrow_account]` and is owned by the
+    /// PDA derived from the escrow account's key (seeds: `[b"escrow", escrow_account]`).
+    /// When offering native SOL, the vault is a program-owned account holding the lamports.
+    /// A basket trade offers and/or requests extra tokens on top of the first ones. Each
+    /// extra offered token gets its own vault at the PDA `[b"vault", escrow_account, mint]`.
+    /// When offering an NFT, the offered mint must have a supply of 1, 0 decimals and no
+    /// mint authority (so no more tokens can ever be minted).
+    /// Either side can be an SPL Token or a Token-2022 token. The escrow saves which token
+    /// program each side belongs to (the requested side's is the owner of account 3), and the
+    /// extra tokens of a basket trade must belong to the same token program as their side.
+    /// Token-2022 mints may only use the transfer fee, mint close authority, interest
+    /// bearing, metadata and group extensions (e.g., non-transferable mints or mints with
+    /// a transfer hook are rejected, since the trade could never settle).
+    /// The escrow also saves the decimals of every token, so later transfers are rejected
+    /// when a client passes in a mint with different decimals.
     ///
     ///
     /// Accounts expected:
     ///
-    /// 0. `[signer]` The account of the person initializing the escrow (Alice)
-    /// 1. `[writable]` Temporary token account that should be created prior to this instruction and owned by the initializer
-    /// 2. `[]` The initializer's token account for the token they will receive should the trade go through
-    /// 3. `[writable]` The escrow account, it will hold all necessary info about the trade.
-    /// 4. `[]` The rent sysvar. NOTE sysvar can be accessed without passing into entrypoint as an account
-    /// 5. `[]` The token program
+    /// 0. `[signer, writable]` The account of the person initializing the escrow (Alice)
+    /// 1. `[writable]` The initializer's token account holding the tokens they offer
+    ///    (ignored when offering native SOL)
+    /// 2. `[writable]` The escrow's vault, created by this instruction
+    /// 3. `[]` The initializer's token account for the token they will receive should the trade go through
+    ///    (or the initializer's main account when requesting native SOL)
+    /// 4. `[writable]` The escrow account PDA, created by this instruction. It will hold all necessary info about the trade.
+    /// 5. `[]` The rent sysvar. NOTE sysvar can be accessed without passing into entrypoint as an account
+    /// 6. `[]` The token program of the offered token (SPL Token when offering native SOL)
+    /// 7. `[]` The system program
+    /// 8. `[]` The program's config account (PDA `[b"config"]`)
+    /// 9. `[]` The mint of the offered token (not needed when offering native SOL)
+    /// 10. `[]` The offered NFT's Metaplex metadata account (only when `offered_collection` is set)
+    /// 11. `[]` The mint of the requested token (not needed when requesting native SOL)
+    ///
+    /// Then, for every extra offered token of a basket trade:
+    ///
+    /// 0. `[writable]` The initializer's token account holding the tokens they offer
+    /// 1. `[writable]` The token's vault, created by this instruction
+    /// 2. `[]` The mint of the token
+    ///
+    /// And for every extra requested token of a basket trade:
+    ///
+    /// 0. `[]` The initializer's token account for the token they will receive
+    /// 1. `[]` The mint of the token
+    ///
     /// NOTE In the guide, InitEscrow is sometimes referred as an 'endpoint'.
     InitEscrow {
         /// The amount party A expects to receive of token Y from party B
         /// NOTE This amount is provided through the instruction_data (not through an account!)
+        #[allow(dead_code)]
         amount: u64,
+        /// The amount of token X party A deposits into the escrow's vault
+        #[allow(dead_code)]
+        offered_amount: u64,
+        /// Optional unix timestamp after which the trade can no longer be taken and
+        /// anybody may refund the escrow back to the initializer. Must lie in the future
+        #[allow(dead_code)]
+        expires_at: Option<i64>,
+        /// Which side of the trade (if any) is native SOL
+        #[allow(dead_code)]
+        native_side: NativeSide,
+        /// Seed that lets the same initializer open several escrows. The escrow account's
+        /// address is derived from `[b"escrow", initializer, nonce.to_le_bytes()]`
+        #[allow(dead_code)]
+        nonce: u64,
+        /// Optional taker the trade was negotiated with. When set, nobody else can take the trade
+        #[allow(dead_code)]
+        allowed_taker: Option<Pubkey>,
+        /// Optional root of a Merkle tree of the wallets allowed to take the trade
+        /// (see merkle.rs for how the tree is built)
+        #[allow(dead_code)]
+        taker_merkle_root: Option<[u8; 32]>,
+        /// The amounts of the extra tokens offered in a basket trade (at most
+        /// `Escrow::MAX_BASKET_LEGS`). Baskets can't include native SOL
+        #[allow(dead_code)]
+        basket_offered_amounts: Vec<u64>,
+        /// The amounts of the extra tokens requested in a basket trade (at most
+        /// `Escrow::MAX_BASKET_LEGS`)
+        #[allow(dead_code)]
+        basket_requested_amounts: Vec<u64>,
+        /// Whether the initializer offers an NFT. `offered_amount` must then be 1
+        #[allow(dead_code)]
+        offered_nft: bool,
+        /// Optional collection the offered NFT must be a verified member of
+        #[allow(dead_code)]
+        offered_collection: Option<Pubkey>,
     },
 
-    /// Accepts a trade
+    /// Accepts a trade, either completely or partially. The taker pays a share of
+    /// the initializer's expected amount proportional to the amount they take, and
+    /// the escrow is only closed once the whole offer has been taken.
+    /// A basket trade can only be taken as a whole, settling every token at once.
+    /// When the requested token's mint charges a Token-2022 transfer fee, the taker pays it
+    /// on top, so the initializer receives exactly what they asked for (or the trade fails).
+    /// The offered token's transfer fee (if any) comes out of what the taker receives.
+    /// With `use_associated_token_account`, the taker receives the offered tokens in their
+    /// associated token accounts, which get created (at the taker's expense) if needed.
     ///
     ///
     /// Accounts expected:
     ///
     /// 0. `[signer]` The account of the person taking the trade (Bob. Alice is the Initializer)
+    ///    (also writable with `use_associated_token_account`)
     /// 1. `[writable]` The taker's (Bob) token account for the token they send 
+    ///    (or the taker's main account when native SOL was requested)
     /// 2. `[writable]` The taker's token account for the token they will receive should the trade go through
-    /// 3. `[writable]` The PDA's temp token account to get tokens from and eventually close
+    ///    (or any account to receive the lamports when native SOL was offered)
+    ///    (their associated token account with `use_associated_token_account`)
+    /// 3. `[writable]` The escrow's vault to get tokens (or lamports) from and close once fully filled
     /// 4. `[writable]` The initializer's main account to send their rent fees to
     /// 5. `[writable]` The initializer's token account that will receive tokens
+    ///    (or the initializer's main account when native SOL was requested)
     /// 6. `[writable]` The escrow account holding the escrow info
-    /// 7. `[]` The token program
-    /// 8. `[]` The PDA account
+    /// 7. `[]` The token program of the offered token
+    /// 8. `[]` The escrow's PDA account
+    /// 9. `[]` The program's config account (PDA `[b"config"]`)
+    /// 10. `[writable]` The treasury's token account for the requested token that receives the protocol fee
+    ///     (or the treasury itself when native SOL was requested)
+    /// 11. `[]` The system program (only used when native SOL was requested)
+    /// 12. `[]` The token program of the requested token
+    /// 13. `[]` The mint of the offered token, `[writable]` with Token-2022
+    ///     (not needed when native SOL was offered)
+    /// 14. `[]` The mint of the requested token (not needed when native SOL was requested)
+    ///
+    /// Only with `use_associated_token_account`:
+    ///
+    /// 15. `[]` The associated token account program
+    ///
+    /// Then, for every extra offered token of a basket trade (in the escrow's order):
+    ///
+    /// 0. `[writable]` The token's vault, closed by this instruction
+    /// 1. `[writable]` The taker's token account that will receive the token
+    ///    (their associated token account with `use_associated_token_account`)
+    /// 2. `[]` The mint of the token, `[writable]` with Token-2022
+    ///
+    /// And for every extra requested token of a basket trade (in the escrow's order):
+    ///
+    /// 0. `[writable]` The taker's token account for the token they send
+    /// 1. `[writable]` The initializer's token account that will receive the token
+    /// 2. `[writable]` The treasury's token account for the token that receives the protocol fee
+    /// 3. `[]` The mint of the token
     Exchange {
-        /// the amount the taker expects to be paid in the other token, as a u64 because that's the max possible supply of a token
+        /// the amount of the offered token the taker wants to take out of the escrow, as a u64
+        /// because that's the max possible supply of a token
+        #[allow(dead_code)]
         amount: u64,
-    }
+        /// Merkle proof that the taker is on the escrow's allowlist, i.e., the sibling
+        /// hashes from the taker's leaf up to the root. Empty when there is no allowlist
+        #[allow(dead_code)]
+        proof: Vec<[u8; 32]>,
+        /// Whether the taker receives the offered tokens in their associated token accounts,
+        /// creating them if they don't exist yet
+        #[allow(dead_code)]
+        use_associated_token_account: bool,
+    },
 
+    /// Cancels a trade that hasn't been taken yet
+    /// With `use_associated_token_account`, the tokens go back to the initializer's
+    /// associated token accounts, which get created (at the initializer's expense) if needed.
+    ///
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[signer, writable]` The account of the person who initialized the escrow (Alice)
+    /// 1. `[writable]` The escrow's vault to refund tokens (or lamports) from and eventually close
+    /// 2. `[writable]` The initializer's token account that will get the deposited tokens back
+    ///    (their associated token account with `use_associated_token_account`)
+    /// 3. `[writable]` The escrow account holding the escrow info
+    /// 4. `[]` The token program of the offered token
+    /// 5. `[]` The escrow's PDA account
+    /// 6. `[]` The mint of the offered token, `[writable]` with Token-2022
+    ///    (not needed when native SOL was offered)
+    ///
+    /// Only with `use_associated_token_account`:
+    ///
+    /// 7. `[]` The system program
+    /// 8. `[]` The associated token account program
+    ///
+    /// Then, for every extra offered token of a basket trade (in the escrow's order):
+    ///
+    /// 0. `[writable]` The token's vault, closed by this instruction
+    /// 1. `[writable]` The initializer's token account that will get the tokens back
+    ///    (their associated token account with `use_associated_token_account`)
+    /// 2. `[]` The mint of the token, `[writable]` with Token-2022
+    Cancel {
+        /// Whether the initializer gets the tokens back in their associated token accounts,
+        /// creating them if they don't exist anymore
+        #[allow(dead_code)]
+        use_associated_token_account: bool,
+    },
+
+    /// Refunds an expired trade back to the initializer. Anyone can call this
+    /// (e.g., a crank) once `expires_at` has passed.
+    /// With `use_associated_token_account`, the tokens go back to the initializer's
+    /// associated token accounts, which get created (at the payer's expense) if needed.
+    ///
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[writable]` The escrow's vault to refund tokens (or lamports) from and eventually close
+    /// 1. `[writable]` A token account owned by the initializer that will get the deposited tokens back
+    ///    (their associated token account with `use_associated_token_account`)
+    /// 2. `[writable]` The initializer's main account to send their rent fees to
+    /// 3. `[writable]` The escrow account holding the escrow info
+    /// 4. `[]` The token program of the offered token
+    /// 5. `[]` The escrow's PDA account
+    /// 6. `[]` The mint of the offered token, `[writable]` with Token-2022
+    ///    (not needed when native SOL was offered)
+    ///
+    /// Only with `use_associated_token_account`:
+    ///
+    /// 7. `[signer, writable]` The account paying for the associated token accounts
+    /// 8. `[]` The system program
+    /// 9. `[]` The associated token account program
+    ///
+    /// Then, for every extra offered token of a basket trade (in the escrow's order):
+    ///
+    /// 0. `[writable]` The token's vault, closed by this instruction
+    /// 1. `[writable]` A token account owned by the initializer that will get the tokens back
+    ///    (their associated token account with `use_associated_token_account`)
+    /// 2. `[]` The mint of the token, `[writable]` with Token-2022
+    RefundExpired {
+        /// Whether the initializer gets the tokens back in their associated token accounts,
+        /// creating them if they don't exist anymore
+        #[allow(dead_code)]
+        use_associated_token_account: bool,
+    },
+
+    /// Creates the program's config account holding the protocol fee settings.
+    /// Can only be called once, since the config account lives at the PDA `[b"config"]`.
+    /// Only the program's upgrade authority may call it, and becomes the config's admin.
+    /// NOTE So create the config before making the program immutable.
+    ///
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[signer, writable]` The program's upgrade authority paying for the config account, i.e., the admin
+    /// 1. `[writable]` The config account PDA, created by this instruction
+    /// 2. `[]` The system program
+    /// 3. `[]` The program's ProgramData account (PDA `[program_id]` of the upgradeable BPF loader)
+    InitConfig {
+        /// The protocol fee taken from every exchange's payment, in basis points
+        /// (at most `Config::MAX_FEE_BPS`)
+        #[allow(dead_code)]
+        fee_bps: u16,
+        /// The wallet collecting the protocol fees
+        #[allow(dead_code)]
+        treasury: Pubkey,
+    },
+
+    /// Changes the program's operational parameters
+    ///
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[signer]` The config's admin
+    /// 1. `[writable]` The config account
+    UpdateConfig {
+        /// The new protocol fee, in basis points (at most `Config::MAX_FEE_BPS`)
+        #[allow(dead_code)]
+        fee_bps: u16,
+        /// The new wallet collecting the protocol fees
+        #[allow(dead_code)]
+        treasury: Pubkey,
+    },
+
+    /// Hands the config's admin authority over to another account
+    ///
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[signer]` The config's current admin
+    /// 1. `[writable]` The config account
+    TransferAdmin {
+        /// The new admin
+        #[allow(dead_code)]
+        new_admin: Pubkey,
+    },
+
+    /// Pauses or unpauses the program. While paused, `InitEscrow` and `Exchange`
+    /// fail, but `Cancel` and `RefundExpired` keep working.
+    ///
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[signer]` The config's admin
+    /// 1. `[writable]` The config account
+    SetPaused {
+        /// Whether the program should be paused
+        #[allow(dead_code)]
+        paused: bool,
+    },
+
+    /// Upgrades an escrow account opened before the account data was versioned (a legacy
+    /// escrow) to the latest layout, and hands its vault over to the escrow's own PDA.
+    /// The offered tokens stay in the vault, which keeps its address. Anybody can call this.
+    ///
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[signer, writable]` The account paying for the extra rent
+    /// 1. `[writable]` The legacy escrow account
+    /// 2. `[]` The system program
+    /// 3. `[writable]` The escrow's vault (the legacy temp token account)
+    /// 4. `[]` The initializer's token account that will receive tokens
+    /// 5. `[]` The legacy PDA `[b"escrow"]` that currently owns the vault
+    /// 6. `[]` The token program
+    /// 7. `[]` The mint of the offered token, so its decimals can be saved
+    /// 8. `[]` The mint of the requested token, so its decimals can be saved
+    MigrateEscrow,
 }
 
 impl EscrowInstruction {
     /// Unpacks a byte buffer into a [EscrowInstruction](enum.EscrowInstruction.html).
     pub fn unpack(input: &[u8]) -> Result<Self, ProgramError> {
-        // NOTE unpack chooses which instruction to build and then builds and returns
-        // that instruction.
-        // NOTE The first byte is 'tag' and the rest of slice is 'rest'
-        // 'tag' tells unpack how to decode the 'rest' of the slice.
-        let (tag, rest) = input.split_first().ok_or(InvalidInstruction)?;
-
-        Ok(match tag {
-            0 => Self::InitEscrow {
-                amount: Self::unpack_amount(rest)?,
+        // NOTE Borsh writes the variant's index (our 'tag') as the first byte, followed
+        // by the variant's fields in the order they are declared. So the tags stay the
+        // same as before: 0 = InitEscrow, 1 = Exchange, etc.
+        // NOTE try_from_slice() also fails if there are bytes left over after decoding,
+        // so a truncated or padded instruction_data is rejected.
+        Self::try_from_slice(input).map_err(|_| InvalidInstruction.into())
+    }
+
+    /// Packs a [EscrowInstruction](enum.EscrowInstruction.html) into a byte buffer.
+    /// NOTE This is the exact reverse of unpack(), so clients don't have to lay out
+    /// the instruction_data by hand.
+    pub fn pack(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(size_of::<Self>());
+        // Writing into a Vec can't fail
+        self.serialize(&mut buf).unwrap();
+        buf
+    }
+}
+
+/// Finds the escrow (state) account of an initializer for the given nonce
+pub fn find_escrow_address(program_id: &Pubkey, initializer: &Pubkey, nonce: u64) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[b"escrow", initializer.as_ref(), &nonce.to_le_bytes()], program_id)
+}
+
+/// Finds the vault holding an escrow's offered tokens (or lamports)
+pub fn find_vault_address(program_id: &Pubkey, escrow: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[b"vault", escrow.as_ref()], program_id)
+}
+
+/// Finds the vault holding one of the extra offered tokens of a basket escrow
+pub fn find_basket_vault_address(program_id: &Pubkey, escrow: &Pubkey, mint: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[b"vault", escrow.as_ref(), mint.as_ref()], program_id)
+}
+
+/// Finds the PDA that has authority over an escrow's vault
+pub fn find_escrow_authority(program_id: &Pubkey, escrow: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[b"escrow", escrow.as_ref()], program_id)
+}
+
+/// Finds the single PDA that owned the vaults of legacy (unversioned) escrows
+pub fn find_legacy_escrow_authority(program_id: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[b"escrow"], program_id)
+}
+
+/// Finds the program's config account
+pub fn find_config_address(program_id: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[b"config"], program_id)
+}
+
+/// Finds the ProgramData account holding the program's upgrade authority
+pub fn find_program_data_address(program_id: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[program_id.as_ref()], &bpf_loader_upgradeable::id())
+}
+
+// Instruction builders for clients and other programs, mirroring spl_token::instruction.
+// NOTE They derive the escrow, PDA and config addresses themselves, so callers only
+// pass in the accounts they actually own.
+// NOTE Only InitEscrow derives the vault. Exchange and the refunds take the vault saved in
+// the escrow account (Escrow::temp_token_account_pubkey) as is, since a migrated legacy
+// escrow keeps its original temp token account. The same goes for the vaults of a basket
+// escrow's extra offered tokens (see Escrow::basket_offered).
+
+/// An extra token offered in a basket `InitEscrow`
+pub struct InitOfferedLeg {
+    /// The initializer's token account holding the tokens they offer
+    pub initializers_sending_token_account: Pubkey,
+    /// The mint of the token
+    pub mint: Pubkey,
+    /// How many tokens the initializer offers
+    pub amount: u64,
+}
+
+/// An extra token requested in a basket `InitEscrow`
+pub struct InitRequestedLeg {
+    /// The initializer's token account for the token they will receive
+    pub initializers_token_to_receive_account: Pubkey,
+    /// The mint of the token
+    pub mint: Pubkey,
+    /// How many tokens the initializer requests
+    pub amount: u64,
+}
+
+/// The accounts of an extra offered token when exchanging or refunding a basket escrow
+pub struct OfferedLeg {
+    /// The token's vault, saved in the escrow account
+    pub vault: Pubkey,
+    /// The token account receiving the vault's tokens
+    pub token_account: Pubkey,
+    /// The mint of the token
+    pub mint: Pubkey,
+}
+
+/// The accounts of an extra requested token when exchanging a basket escrow
+pub struct RequestedLeg {
+    /// The taker's token account for the token they send
+    pub takers_sending_token_account: Pubkey,
+    /// The initializer's token account that will receive the token, saved in the escrow account
+    pub initializers_token_to_receive_account: Pubkey,
+    /// The treasury's token account for the token that receives the protocol fee
+    pub fee_recipient: Pubkey,
+    /// The mint of the token
+    pub mint: Pubkey,
+}
+
+/// Creates an `InitEscrow` instruction.
+/// When offering native SOL, `initializers_sending_token_account` is ignored (pass the
+/// initializer) and so is `offered_mint`. When requesting native SOL,
+/// `initializers_token_to_receive_account` must be the initializer and `requested_mint`
+/// is ignored.
+/// `basket_offered` and `basket_requested` are empty unless it's a basket trade.
+/// `offered_collection` can only be set when offering an NFT.
+/// `token_program_id` is the offered token's program (`spl_token::id()` when offering native SOL).
+#[allow(clippy::too_many_arguments)]
+pub fn init_escrow(
+    program_id: &Pubkey,
+    token_program_id: &Pubkey,
+    initializer: &Pubkey,
+    initializers_sending_token_account: &Pubkey,
+    initializers_token_to_receive_account: &Pubkey,
+    offered_mint: &Pubkey,
+    requested_mint: &Pubkey,
+    amount: u64,
+    offered_amount: u64,
+    expires_at: Option<i64>,
+    native_side: NativeSide,
+    nonce: u64,
+    allowed_taker: Option<Pubkey>,
+    taker_merkle_root: Option<[u8; 32]>,
+    basket_offered: &[InitOfferedLeg],
+    basket_requested: &[InitRequestedLeg],
+    offered_nft: bool,
+    offered_collection: Option<Pubkey>,
+) -> Result<Instruction, ProgramError> {
+    let data = EscrowInstruction::InitEscrow {
+        amount,
+        offered_amount,
+        expires_at,
+        native_side,
+        nonce,
+        allowed_taker,
+        taker_merkle_root,
+        basket_offered_amounts: basket_offered.iter().map(|leg| leg.amount).collect(),
+        basket_requested_amounts: basket_requested.iter().map(|leg| leg.amount).collect(),
+        offered_nft,
+        offered_collection,
+    }
+    .pack();
+
+    let (escrow, _) = find_escrow_address(program_id, initializer, nonce);
+    let (vault, _) = find_vault_address(program_id, &escrow);
+    let (config, _) = find_config_address(program_id);
+
+    let mut accounts = vec![
+        AccountMeta::new(*initializer, true),
+        AccountMeta::new(*initializers_sending_token_account, false),
+        AccountMeta::new(vault, false),
+        AccountMeta::new_readonly(*initializers_token_to_receive_account, false),
+        AccountMeta::new(escrow, false),
+        AccountMeta::new_readonly(sysvar::rent::id(), false),
+        AccountMeta::new_readonly(*token_program_id, false),
+        AccountMeta::new_readonly(system_program::id(), false),
+        AccountMeta::new_readonly(config, false),
+    ];
+    if native_side != NativeSide::Offered {
+        accounts.push(AccountMeta::new_readonly(*offered_mint, false));
+    }
+    if offered_collection.is_some() {
+        let (offered_metadata, _) = find_metadata_address(offered_mint);
+        accounts.push(AccountMeta::new_readonly(offered_metadata, false));
+    }
+    if native_side != NativeSide::Requested {
+        accounts.push(AccountMeta::new_readonly(*requested_mint, false));
+    }
+    for leg in basket_offered {
+        let (leg_vault, _) = find_basket_vault_address(program_id, &escrow, &leg.mint);
+        accounts.push(AccountMeta::new(leg.initializers_sending_token_account, false));
+        accounts.push(AccountMeta::new(leg_vault, false));
+        accounts.push(AccountMeta::new_readonly(leg.mint, false));
+    }
+    for leg in basket_requested {
+        accounts.push(AccountMeta::new_readonly(leg.initializers_token_to_receive_account, false));
+        accounts.push(AccountMeta::new_readonly(leg.mint, false));
+    }
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+/// Creates an `Exchange` instruction.
+/// `vault`, `initializer` and `initializers_token_to_receive_account` are saved in the escrow
+/// account, and `fee_recipient` is the treasury's account for the requested token
+/// (or the treasury itself when native SOL was requested).
+/// `basket_offered` and `basket_requested` follow the escrow's basket legs, in order.
+/// The token program ids are the ones saved in the escrow account for either side, and
+/// `offered_mint` and `requested_mint` are None when that side is native SOL.
+/// With `use_associated_token_account`, `takers_receiving_token_account` and the basket's
+/// receiving token accounts must be the taker's associated token accounts
+/// (see `token::get_associated_token_address`), which the program creates if needed.
+#[allow(clippy::too_many_arguments)]
+pub fn exchange(
+    program_id: &Pubkey,
+    offered_token_program_id: &Pubkey,
+    requested_token_program_id: &Pubkey,
+    taker: &Pubkey,
+    takers_sending_token_account: &Pubkey,
+    takers_receiving_token_account: &Pubkey,
+    initializer: &Pubkey,
+    initializers_token_to_receive_account: &Pubkey,
+    escrow: &Pubkey,
+    vault: &Pubkey,
+    fee_recipient: &Pubkey,
+    offered_mint: Option<&Pubkey>,
+    requested_mint: Option<&Pubkey>,
+    amount: u64,
+    proof: Vec<[u8; 32]>,
+    basket_offered: &[OfferedLeg],
+    basket_requested: &[RequestedLeg],
+    use_associated_token_account: bool,
+) -> Result<Instruction, ProgramError> {
+    let data = EscrowInstruction::Exchange { amount, proof, use_associated_token_account }.pack();
+
+    let (pda, _) = find_escrow_authority(program_id, escrow);
+    let (config, _) = find_config_address(program_id);
+
+    // NOTE The system program is only used when native SOL was requested, but passing
+    // it in regardless doesn't hurt (and keeps the basket legs at a fixed position).
+    let mut accounts = vec![
+        // The taker pays for their new associated token accounts
+        if use_associated_token_account {
+            AccountMeta::new(*taker, true)
+        } else {
+            AccountMeta::new_readonly(*taker, true)
+        },
+        AccountMeta::new(*takers_sending_token_account, false),
+        AccountMeta::new(*takers_receiving_token_account, false),
+        AccountMeta::new(*vault, false),
+        AccountMeta::new(*initializer, false),
+        AccountMeta::new(*initializers_token_to_receive_account, false),
+        AccountMeta::new(*escrow, false),
+        AccountMeta::new_readonly(*offered_token_program_id, false),
+        AccountMeta::new_readonly(pda, false),
+        AccountMeta::new_readonly(config, false),
+        AccountMeta::new(*fee_recipient, false),
+        AccountMeta::new_readonly(system_program::id(), false),
+        AccountMeta::new_readonly(*requested_token_program_id, false),
+    ];
+    if let Some(offered_mint) = offered_mint {
+        accounts.push(offered_mint_meta(offered_token_program_id, offered_mint));
+    }
+    if let Some(requested_mint) = requested_mint {
+        accounts.push(AccountMeta::new_readonly(*requested_mint, false));
+    }
+    if use_associated_token_account {
+        accounts.push(AccountMeta::new_readonly(spl_associated_token_account::id(), false));
+    }
+    push_offered_legs(&mut accounts, offered_token_program_id, basket_offered);
+    for leg in basket_requested {
+        accounts.push(AccountMeta::new(leg.takers_sending_token_account, false));
+        accounts.push(AccountMeta::new(leg.initializers_token_to_receive_account, false));
+        accounts.push(AccountMeta::new(leg.fee_recipient, false));
+        accounts.push(AccountMeta::new_readonly(leg.mint, false));
+    }
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+/// Creates a `Cancel` instruction. `vault` is saved in the escrow account,
+/// `token_program_id` is the offered token's program and `offered_mint` is None when
+/// native SOL was offered.
+/// With `use_associated_token_account`, the refund token accounts must be the initializer's
+/// associated token accounts, which the program creates if needed.
+#[allow(clippy::too_many_arguments)]
+pub fn cancel(
+    program_id: &Pubkey,
+    token_program_id: &Pubkey,
+    initializer: &Pubkey,
+    initializers_refund_token_account: &Pubkey,
+    escrow: &Pubkey,
+    vault: &Pubkey,
+    offered_mint: Option<&Pubkey>,
+    basket_offered: &[OfferedLeg],
+    use_associated_token_account: bool,
+) -> Result<Instruction, ProgramError> {
+    let data = EscrowInstruction::Cancel { use_associated_token_account }.pack();
+
+    let (pda, _) = find_escrow_authority(program_id, escrow);
+
+    let mut accounts = vec![
+        AccountMeta::new(*initializer, true),
+        AccountMeta::new(*vault, false),
+        AccountMeta::new(*initializers_refund_token_account, false),
+        AccountMeta::new(*escrow, false),
+        AccountMeta::new_readonly(*token_program_id, false),
+        AccountMeta::new_readonly(pda, false),
+    ];
+    if let Some(offered_mint) = offered_mint {
+        accounts.push(offered_mint_meta(token_program_id, offered_mint));
+    }
+    if use_associated_token_account {
+        push_associated_token_account_programs(&mut accounts);
+    }
+    push_offered_legs(&mut accounts, token_program_id, basket_offered);
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+/// Creates a `RefundExpired` instruction. `vault` is saved in the escrow account,
+/// `token_program_id` is the offered token's program and `offered_mint` is None when
+/// native SOL was offered.
+/// Passing in `associated_token_account_payer` refunds into the initializer's associated
+/// token accounts (which the refund token accounts must then be), created at its expense if needed.
+#[allow(clippy::too_many_arguments)]
+pub fn refund_expired(
+    program_id: &Pubkey,
+    token_program_id: &Pubkey,
+    initializers_refund_token_account: &Pubkey,
+    initializer: &Pubkey,
+    escrow: &Pubkey,
+    vault: &Pubkey,
+    offered_mint: Option<&Pubkey>,
+    basket_offered: &[OfferedLeg],
+    associated_token_account_payer: Option<&Pubkey>,
+) -> Result<Instruction, ProgramError> {
+    let data = EscrowInstruction::RefundExpired {
+        use_associated_token_account: associated_token_account_payer.is_some(),
+    }
+    .pack();
+
+    let (pda, _) = find_escrow_authority(program_id, escrow);
+
+    let mut accounts = vec![
+        AccountMeta::new(*vault, false),
+        AccountMeta::new(*initializers_refund_token_account, false),
+        AccountMeta::new(*initializer, false),
+        AccountMeta::new(*escrow, false),
+        AccountMeta::new_readonly(*token_program_id, false),
+        AccountMeta::new_readonly(pda, false),
+    ];
+    if let Some(offered_mint) = offered_mint {
+        accounts.push(offered_mint_meta(token_program_id, offered_mint));
+    }
+    if let Some(payer) = associated_token_account_payer {
+        accounts.push(AccountMeta::new(*payer, true));
+        push_associated_token_account_programs(&mut accounts);
+    }
+    push_offered_legs(&mut accounts, token_program_id, basket_offered);
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+/// Creates an `InitConfig` instruction. `admin` must be the program's upgrade authority.
+pub fn init_config(
+    program_id: &Pubkey,
+    admin: &Pubkey,
+    fee_bps: u16,
+    treasury: &Pubkey,
+) -> Result<Instruction, ProgramError> {
+    let data = EscrowInstruction::InitConfig { fee_bps, treasury: *treasury }.pack();
+
+    let (config, _) = find_config_address(program_id);
+    let (program_data, _) = find_program_data_address(program_id);
+
+    let accounts = vec![
+        AccountMeta::new(*admin, true),
+        AccountMeta::new(config, false),
+        AccountMeta::new_readonly(system_program::id(), false),
+        AccountMeta::new_readonly(program_data, false),
+    ];
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+/// Creates an `UpdateConfig` instruction
+pub fn update_config(
+    program_id: &Pubkey,
+    admin: &Pubkey,
+    fee_bps: u16,
+    treasury: &Pubkey,
+) -> Result<Instruction, ProgramError> {
+    let data = EscrowInstruction::UpdateConfig { fee_bps, treasury: *treasury }.pack();
+    Ok(admin_instruction(program_id, admin, data))
+}
+
+/// Creates a `TransferAdmin` instruction
+pub fn transfer_admin(
+    program_id: &Pubkey,
+    admin: &Pubkey,
+    new_admin: &Pubkey,
+) -> Result<Instruction, ProgramError> {
+    let data = EscrowInstruction::TransferAdmin { new_admin: *new_admin }.pack();
+    Ok(admin_instruction(program_id, admin, data))
+}
+
+/// Creates a `SetPaused` instruction
+pub fn set_paused(
+    program_id: &Pubkey,
+    admin: &Pubkey,
+    paused: bool,
+) -> Result<Instruction, ProgramError> {
+    let data = EscrowInstruction::SetPaused { paused }.pack();
+    Ok(admin_instruction(program_id, admin, data))
+}
+
+/// Creates a `MigrateEscrow` instruction. `vault` and
+/// `initializers_token_to_receive_account` are saved in the legacy escrow account, and
+/// the mints are the ones of the vault and the receiving token account.
+#[allow(clippy::too_many_arguments)]
+pub fn migrate_escrow(
+    program_id: &Pubkey,
+    payer: &Pubkey,
+    escrow: &Pubkey,
+    vault: &Pubkey,
+    initializers_token_to_receive_account: &Pubkey,
+    offered_mint: &Pubkey,
+    requested_mint: &Pubkey,
+) -> Result<Instruction, ProgramError> {
+    let data = EscrowInstruction::MigrateEscrow.pack();
+
+    let (legacy_pda, _) = find_legacy_escrow_authority(program_id);
+
+    let accounts = vec![
+        AccountMeta::new(*payer, true),
+        AccountMeta::new(*escrow, false),
+        AccountMeta::new_readonly(system_program::id(), false),
+        AccountMeta::new(*vault, false),
+        AccountMeta::new_readonly(*initializers_token_to_receive_account, false),
+        AccountMeta::new_readonly(legacy_pda, false),
+        AccountMeta::new_readonly(spl_token::id(), false),
+        AccountMeta::new_readonly(*offered_mint, false),
+        AccountMeta::new_readonly(*requested_mint, false),
+    ];
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+// Exchange, Cancel and RefundExpired all take a (vault, token account, mint) triple for
+// every extra offered token of a basket escrow
+fn push_offered_legs(accounts: &mut Vec<AccountMeta>, token_program_id: &Pubkey, basket_offered: &[OfferedLeg]) {
+    for leg in basket_offered {
+        accounts.push(AccountMeta::new(leg.vault, false));
+        accounts.push(AccountMeta::new(leg.token_account, false));
+        accounts.push(offered_mint_meta(token_program_id, &leg.mint));
+    }
+}
+
+// Only Token-2022 writes to an offered mint, when harvesting the transfer fees withheld in
+// a vault before closing it. SPL Token mints stay read-only, so trades of the same token
+// don't have to wait on each other's write lock.
+fn offered_mint_meta(token_program_id: &Pubkey, mint: &Pubkey) -> AccountMeta {
+    if *token_program_id == spl_token_2022::id() {
+        AccountMeta::new(*mint, false)
+    } else {
+        AccountMeta::new_readonly(*mint, false)
+    }
+}
+
+// Cancel and RefundExpired need both programs to create associated token accounts
+fn push_associated_token_account_programs(accounts: &mut Vec<AccountMeta>) {
+    accounts.push(AccountMeta::new_readonly(system_program::id(), false));
+    accounts.push(AccountMeta::new_readonly(spl_associated_token_account::id(), false));
+}
+
+// The admin-only instructions all take the same two accounts
+fn admin_instruction(program_id: &Pubkey, admin: &Pubkey, data: Vec<u8>) -> Instruction {
+    let (config, _) = find_config_address(program_id);
+
+    Instruction {
+        program_id: *program_id,
+        accounts: vec![
+            AccountMeta::new_readonly(*admin, true),
+            AccountMeta::new(config, false),
+        ],
+        data,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // The keys of an instruction's accounts, in order
+    fn keys(instruction: &Instruction) -> Vec<Pubkey> {
+        instruction.accounts.iter().map(|meta| meta.pubkey).collect()
+    }
+
+    #[test]
+    fn unpack_reverses_pack() {
+        let instructions = vec![
+            EscrowInstruction::InitEscrow {
+                amount: 5_000,
+                offered_amount: 100,
+                expires_at: Some(1_700_000_000),
+                native_side: NativeSide::Requested,
+                nonce: 7,
+                allowed_taker: Some(Pubkey::new_unique()),
+                taker_merkle_root: Some([3; 32]),
+                basket_offered_amounts: vec![1, 2],
+                basket_requested_amounts: vec![3],
+                offered_nft: true,
+                offered_collection: Some(Pubkey::new_unique()),
             },
-            1 => Self::Exchange {
-                amount: Self::unpack_amount(rest)?,
+            EscrowInstruction::Exchange {
+                amount: 42,
+                proof: vec![[1; 32], [2; 32]],
+                use_associated_token_account: true,
             },
-            _ => return Err(InvalidInstruction.into()),
-        })
-    }
-
-    fn unpack_amount(input: &[u8]) -> Result<u64, ProgramError> {
-        // Decodes the 'rest' of the slice to get a u64 representing amount
-        let amount = input
-            .get(..8)
-            .and_then(|slice| slice.try_into().ok())
-            .map(u64::from_le_bytes)
-            .ok_or(InvalidInstruction)?;
-        Ok(amount)
+            EscrowInstruction::Cancel { use_associated_token_account: true },
+            EscrowInstruction::RefundExpired { use_associated_token_account: false },
+            EscrowInstruction::InitConfig { fee_bps: 30, treasury: Pubkey::new_unique() },
+            EscrowInstruction::UpdateConfig { fee_bps: 10_000, treasury: Pubkey::new_unique() },
+            EscrowInstruction::TransferAdmin { new_admin: Pubkey::new_unique() },
+            EscrowInstruction::SetPaused { paused: true },
+            EscrowInstruction::MigrateEscrow,
+        ];
+        for (tag, instruction) in instructions.into_iter().enumerate() {
+            let data = instruction.pack();
+            assert_eq!(data[0], tag as u8);
+            assert_eq!(EscrowInstruction::unpack(&data), Ok(instruction));
+        }
+    }
+
+    #[test]
+    fn unpack_rejects_bad_data() {
+        let mut data = EscrowInstruction::SetPaused { paused: true }.pack();
+        data.push(0);
+        assert_eq!(EscrowInstruction::unpack(&data), Err(InvalidInstruction.into()));
+        assert_eq!(EscrowInstruction::unpack(&[]), Err(InvalidInstruction.into()));
+        assert_eq!(EscrowInstruction::unpack(&[9]), Err(InvalidInstruction.into()));
+    }
+
+    #[test]
+    fn init_escrow_accounts_are_where_the_processor_expects_them() {
+        let program_id = Pubkey::new_unique();
+        let initializer = Pubkey::new_unique();
+        let (sending, receiving) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let (offered_mint, requested_mint) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let offered_leg = InitOfferedLeg {
+            initializers_sending_token_account: Pubkey::new_unique(),
+            mint: Pubkey::new_unique(),
+            amount: 1,
+        };
+        let requested_leg = InitRequestedLeg {
+            initializers_token_to_receive_account: Pubkey::new_unique(),
+            mint: Pubkey::new_unique(),
+            amount: 2,
+        };
+        let collection = Pubkey::new_unique();
+        let instruction = init_escrow(
+            &program_id,
+            &spl_token::id(),
+            &initializer,
+            &sending,
+            &receiving,
+            &offered_mint,
+            &requested_mint,
+            5_000,
+            100,
+            None,
+            NativeSide::None,
+            7,
+            None,
+            None,
+            std::slice::from_ref(&offered_leg),
+            std::slice::from_ref(&requested_leg),
+            true,
+            Some(collection),
+        )
+        .unwrap();
+
+        let (escrow, _) = find_escrow_address(&program_id, &initializer, 7);
+        let (vault, _) = find_vault_address(&program_id, &escrow);
+        let (config, _) = find_config_address(&program_id);
+        let (leg_vault, _) = find_basket_vault_address(&program_id, &escrow, &offered_leg.mint);
+        assert_eq!(
+            keys(&instruction),
+            vec![
+                initializer,
+                sending,
+                vault,
+                receiving,
+                escrow,
+                sysvar::rent::id(),
+                spl_token::id(),
+                system_program::id(),
+                // NOTE Processor::process checks accounts.get(8) to reject InitEscrow while paused
+                config,
+                offered_mint,
+                find_metadata_address(&offered_mint).0,
+                requested_mint,
+                offered_leg.initializers_sending_token_account,
+                leg_vault,
+                offered_leg.mint,
+                requested_leg.initializers_token_to_receive_account,
+                requested_leg.mint,
+            ]
+        );
+        assert!(instruction.accounts[0].is_signer);
+    }
+
+    #[test]
+    fn exchange_accounts_are_where_the_processor_expects_them() {
+        let program_id = Pubkey::new_unique();
+        let [taker, sending, receiving, initializer, to_receive, escrow, vault, fee_recipient, offered_mint, requested_mint] =
+            [(); 10].map(|_| Pubkey::new_unique());
+        let offered_leg = OfferedLeg { vault: Pubkey::new_unique(), token_account: Pubkey::new_unique(), mint: Pubkey::new_unique() };
+        let requested_leg = RequestedLeg {
+            takers_sending_token_account: Pubkey::new_unique(),
+            initializers_token_to_receive_account: Pubkey::new_unique(),
+            fee_recipient: Pubkey::new_unique(),
+            mint: Pubkey::new_unique(),
+        };
+        let instruction = exchange(
+            &program_id,
+            &spl_token::id(),
+            &spl_token_2022::id(),
+            &taker,
+            &sending,
+            &receiving,
+            &initializer,
+            &to_receive,
+            &escrow,
+            &vault,
+            &fee_recipient,
+            Some(&offered_mint),
+            Some(&requested_mint),
+            10,
+            vec![],
+            std::slice::from_ref(&offered_leg),
+            std::slice::from_ref(&requested_leg),
+            true,
+        )
+        .unwrap();
+
+        let (pda, _) = find_escrow_authority(&program_id, &escrow);
+        let (config, _) = find_config_address(&program_id);
+        assert_eq!(
+            keys(&instruction),
+            vec![
+                taker,
+                sending,
+                receiving,
+                vault,
+                initializer,
+                to_receive,
+                escrow,
+                spl_token::id(),
+                pda,
+                // NOTE Processor::process checks accounts.get(9) to reject Exchange while paused
+                config,
+                fee_recipient,
+                system_program::id(),
+                spl_token_2022::id(),
+                offered_mint,
+                requested_mint,
+                spl_associated_token_account::id(),
+                offered_leg.vault,
+                offered_leg.token_account,
+                offered_leg.mint,
+                requested_leg.takers_sending_token_account,
+                requested_leg.initializers_token_to_receive_account,
+                requested_leg.fee_recipient,
+                requested_leg.mint,
+            ]
+        );
+        // The taker pays for their associated token accounts, and SPL Token mints stay read-only
+        assert!(instruction.accounts[0].is_signer && instruction.accounts[0].is_writable);
+        assert!(!instruction.accounts[13].is_writable);
+        assert!(!instruction.accounts[18].is_writable);
+    }
+
+    #[test]
+    fn cancel_accounts_are_where_the_processor_expects_them() {
+        let program_id = Pubkey::new_unique();
+        let [initializer, refund, escrow, vault, offered_mint] = [(); 5].map(|_| Pubkey::new_unique());
+        let offered_leg = OfferedLeg { vault: Pubkey::new_unique(), token_account: Pubkey::new_unique(), mint: Pubkey::new_unique() };
+        let instruction = cancel(
+            &program_id,
+            &spl_token_2022::id(),
+            &initializer,
+            &refund,
+            &escrow,
+            &vault,
+            Some(&offered_mint),
+            std::slice::from_ref(&offered_leg),
+            true,
+        )
+        .unwrap();
+
+        let (pda, _) = find_escrow_authority(&program_id, &escrow);
+        assert_eq!(
+            keys(&instruction),
+            vec![
+                initializer,
+                vault,
+                refund,
+                escrow,
+                spl_token_2022::id(),
+                pda,
+                offered_mint,
+                system_program::id(),
+                spl_associated_token_account::id(),
+                offered_leg.vault,
+                offered_leg.token_account,
+                offered_leg.mint,
+            ]
+        );
+        assert!(instruction.accounts[0].is_signer);
+        // Token-2022 may harvest the vaults' withheld transfer fees into the mints
+        assert!(instruction.accounts[6].is_writable);
+        assert!(instruction.accounts[11].is_writable);
+    }
+
+    #[test]
+    fn refund_expired_accounts_are_where_the_processor_expects_them() {
+        let program_id = Pubkey::new_unique();
+        let [refund, initializer, escrow, vault, offered_mint, payer] = [(); 6].map(|_| Pubkey::new_unique());
+        let instruction = refund_expired(
+            &program_id,
+            &spl_token::id(),
+            &refund,
+            &initializer,
+            &escrow,
+            &vault,
+            Some(&offered_mint),
+            &[],
+            Some(&payer),
+        )
+        .unwrap();
+
+        let (pda, _) = find_escrow_authority(&program_id, &escrow);
+        assert_eq!(
+            keys(&instruction),
+            vec![
+                vault,
+                refund,
+                initializer,
+                escrow,
+                spl_token::id(),
+                pda,
+                offered_mint,
+                payer,
+                system_program::id(),
+                spl_associated_token_account::id(),
+            ]
+        );
+        // Anyone can crank an expired escrow, so only the payer signs
+        assert_eq!(
+            instruction.accounts.iter().filter(|meta| meta.is_signer).map(|meta| meta.pubkey).collect::<Vec<_>>(),
+            vec![payer]
+        );
+    }
+
+    #[test]
+    fn config_accounts_are_where_the_processor_expects_them() {
+        let program_id = Pubkey::new_unique();
+        let admin = Pubkey::new_unique();
+        let (config, _) = find_config_address(&program_id);
+        let (program_data, _) = find_program_data_address(&program_id);
+
+        let instruction = init_config(&program_id, &admin, 30, &Pubkey::new_unique()).unwrap();
+        assert_eq!(keys(&instruction), vec![admin, config, system_program::id(), program_data]);
+        for instruction in [
+            update_config(&program_id, &admin, 30, &Pubkey::new_unique()).unwrap(),
+            transfer_admin(&program_id, &admin, &Pubkey::new_unique()).unwrap(),
+            set_paused(&program_id, &admin, true).unwrap(),
+        ] {
+            assert_eq!(keys(&instruction), vec![admin, config]);
+            assert!(instruction.accounts[0].is_signer && instruction.accounts[1].is_writable);
+        }
+    }
+
+    #[test]
+    fn migrate_escrow_accounts_are_where_the_processor_expects_them() {
+        let program_id = Pubkey::new_unique();
+        let [payer, escrow, vault, to_receive, offered_mint, requested_mint] = [(); 6].map(|_| Pubkey::new_unique());
+        let instruction =
+            migrate_escrow(&program_id, &payer, &escrow, &vault, &to_receive, &offered_mint, &requested_mint).unwrap();
+
+        let (legacy_pda, _) = find_legacy_escrow_authority(&program_id);
+        assert_eq!(
+            keys(&instruction),
+            vec![
+                payer,
+                escrow,
+                system_program::id(),
+                vault,
+                to_receive,
+                legacy_pda,
+                spl_token::id(),
+                offered_mint,
+                requested_mint,
+            ]
+        );
     }
 }
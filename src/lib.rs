@@ -1,8 +1,13 @@
 // NOTE MUST register our mods/crates here or won't compile!
+pub mod cpi;
 pub mod error;
 pub mod instruction;
+pub mod merkle;
+pub mod metadata;
 pub mod processor;
 pub mod state;
+pub mod token;
+pub mod validation;
 
 
 // Allow other programs to import this program via Cargo Features
@@ -0,0 +1,99 @@
+// Merkle proofs for escrows that may only be taken by an allowlist of wallets.
+// NOTE Storing several hundred pubkeys in the escrow account would be expensive, so
+// Alice only saves the 32 byte ROOT of a Merkle tree built from the allowed wallets.
+// When Bob takes the trade, he passes in the sibling hashes along the path from his
+// leaf to the root (the proof) and we recompute the root on-chain.
+//
+// How the tree is built (clients MUST build it exactly the same way):
+// - leaf = keccak256(0x00 || taker_pubkey)
+// - node = keccak256(0x01 || min(left, right) || max(left, right))
+// NOTE The prefix bytes make sure a leaf can never be passed off as an inner node.
+// NOTE Sorting each pair means the proof doesn't need to say whether a sibling sits
+// on the left or on the right.
+use solana_program::{keccak::hashv, pubkey::Pubkey};
+
+const LEAF_PREFIX: &[u8] = &[0];
+const NODE_PREFIX: &[u8] = &[1];
+
+/// Whether `proof` proves that `taker` is a leaf of the Merkle tree with the given `root`
+pub fn verify_taker(proof: &[[u8; 32]], root: &[u8; 32], taker: &Pubkey) -> bool {
+    // Hashing is done through the sol_keccak256 syscall, so it's cheap on-chain.
+    let mut computed = hashv(&[LEAF_PREFIX, taker.as_ref()]).to_bytes();
+    for sibling in proof {
+        computed = if computed <= *sibling {
+            hashv(&[NODE_PREFIX, &computed, sibling]).to_bytes()
+        } else {
+            hashv(&[NODE_PREFIX, sibling, &computed]).to_bytes()
+        };
+    }
+    computed == *root
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn leaf(taker: &Pubkey) -> [u8; 32] {
+        hashv(&[LEAF_PREFIX, taker.as_ref()]).to_bytes()
+    }
+
+    fn node(a: &[u8; 32], b: &[u8; 32]) -> [u8; 32] {
+        let (left, right) = if a <= b { (a, b) } else { (b, a) };
+        hashv(&[NODE_PREFIX, left, right]).to_bytes()
+    }
+
+    // A tree of four takers:
+    //         root
+    //       /      \
+    //    n01        n23
+    //   /   \      /   \
+    //  l0   l1    l2   l3
+    struct Tree {
+        takers: [Pubkey; 4],
+        leaves: [[u8; 32]; 4],
+        n01: [u8; 32],
+        n23: [u8; 32],
+        root: [u8; 32],
+    }
+
+    fn tree() -> Tree {
+        let takers = [Pubkey::new_unique(), Pubkey::new_unique(), Pubkey::new_unique(), Pubkey::new_unique()];
+        let leaves = [leaf(&takers[0]), leaf(&takers[1]), leaf(&takers[2]), leaf(&takers[3])];
+        let n01 = node(&leaves[0], &leaves[1]);
+        let n23 = node(&leaves[2], &leaves[3]);
+        let root = node(&n01, &n23);
+        Tree { takers, leaves, n01, n23, root }
+    }
+
+    #[test]
+    fn valid_proofs() {
+        let tree = tree();
+        assert!(verify_taker(&[tree.leaves[1], tree.n23], &tree.root, &tree.takers[0]));
+        assert!(verify_taker(&[tree.leaves[0], tree.n23], &tree.root, &tree.takers[1]));
+        assert!(verify_taker(&[tree.leaves[3], tree.n01], &tree.root, &tree.takers[2]));
+        assert!(verify_taker(&[tree.leaves[2], tree.n01], &tree.root, &tree.takers[3]));
+    }
+
+    #[test]
+    fn wrong_taker() {
+        let tree = tree();
+        assert!(!verify_taker(&[tree.leaves[1], tree.n23], &tree.root, &Pubkey::new_unique()));
+        // Taker 1's proof doesn't work for taker 2 either
+        assert!(!verify_taker(&[tree.leaves[0], tree.n23], &tree.root, &tree.takers[2]));
+    }
+
+    #[test]
+    fn truncated_proof() {
+        let tree = tree();
+        assert!(!verify_taker(&[tree.leaves[1]], &tree.root, &tree.takers[0]));
+        assert!(!verify_taker(&[], &tree.root, &tree.takers[0]));
+    }
+
+    #[test]
+    fn leaf_passed_off_as_node() {
+        let tree = tree();
+        // Pretending the inner node n01 is a taker (with n23 as the proof) doesn't work,
+        // because leaves are hashed with a different prefix than nodes
+        assert!(!verify_taker(&[tree.n23], &tree.root, &Pubkey::new(&tree.n01)));
+    }
+}
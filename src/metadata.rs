@@ -0,0 +1,97 @@
+// Reads the Metaplex Token Metadata accounts of NFTs, so InitEscrow can check which
+// collection an offered NFT belongs to.
+// NOTE Every NFT minted through Metaplex has a metadata account at the PDA
+// `[b"metadata", metadata_program_id, mint]` of the Token Metadata program. Among other
+// things it records the collection the NFT belongs to, and whether the collection's
+// authority verified that (otherwise anybody could claim to be part of any collection).
+// NOTE The mpl-token-metadata crate needs a much newer solana-program than the one we're
+// on, so we decode the fields we need ourselves. The account is Borsh encoded as well.
+use borsh::BorshDeserialize;
+use solana_program::{account_info::AccountInfo, program_error::ProgramError, pubkey::Pubkey};
+
+use crate::{
+    error::EscrowError,
+    validation::{assert_address, assert_owned_by},
+};
+
+// The Token Metadata program, same as spl_token::id() for the token program
+solana_program::declare_id!("metaqbxxUerdq28cj1RbAWkYQm3ybzjb6a8bt518x1s");
+
+/// The first byte of a metadata account (Metaplex's `Key::MetadataV1`)
+pub const METADATA_KEY: u8 = 4;
+
+/// Finds the metadata account of a mint
+pub fn find_metadata_address(mint: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[b"metadata", id().as_ref(), mint.as_ref()], &id())
+}
+
+/// A creator of an NFT, as listed in its metadata
+#[derive(BorshDeserialize, Debug, PartialEq)]
+pub struct Creator {
+    pub address: Pubkey,
+    pub verified: bool,
+    pub share: u8,
+}
+
+/// The NFT's name, symbol, etc.
+#[derive(BorshDeserialize, Debug, PartialEq)]
+pub struct Data {
+    pub name: String,
+    pub symbol: String,
+    pub uri: String,
+    pub seller_fee_basis_points: u16,
+    pub creators: Option<Vec<Creator>>,
+}
+
+/// The collection an NFT belongs to
+#[derive(BorshDeserialize, Debug, PartialEq)]
+pub struct Collection {
+    /// Whether the collection's authority confirmed the NFT belongs to it
+    pub verified: bool,
+    /// The mint of the collection's NFT
+    pub key: Pubkey,
+}
+
+/// The fields of a metadata account every version of the Token Metadata program wrote
+#[derive(BorshDeserialize, Debug, PartialEq)]
+pub struct Metadata {
+    pub key: u8,
+    pub update_authority: Pubkey,
+    pub mint: Pubkey,
+    pub data: Data,
+    pub primary_sale_happened: bool,
+    pub is_mutable: bool,
+}
+
+/// Returns the collection that verified the NFT of the given mint (if any), checking that
+/// `metadata_account` really is the mint's metadata account.
+pub fn verified_collection(metadata_account: &AccountInfo, mint: &Pubkey) -> Result<Option<Pubkey>, ProgramError> {
+    assert_owned_by(metadata_account, &id())?;
+    let (metadata_address, _) = find_metadata_address(mint);
+    assert_address(metadata_account, &metadata_address, EscrowError::InvalidMetadataAccount)?;
+
+    // NOTE Unlike Pack::unpack(), deserialize() reads from the front of the slice and
+    // moves it along, leaving whatever comes after the fields we decoded.
+    let data = metadata_account.data.borrow();
+    let buf = &mut &data[..];
+    let metadata = Metadata::deserialize(buf).map_err(|_| EscrowError::InvalidMetadataAccount)?;
+    if metadata.key != METADATA_KEY || metadata.mint != *mint {
+        return Err(EscrowError::InvalidMetadataAccount.into());
+    }
+
+    // The optional fields that follow were added over time, so older metadata accounts
+    // may end before them: edition_nonce, token_standard and then the collection.
+    let _edition_nonce = deserialize_trailing::<u8>(buf)?;
+    let _token_standard = deserialize_trailing::<u8>(buf)?;
+    let collection = deserialize_trailing::<Collection>(buf)?;
+
+    Ok(collection.filter(|collection| collection.verified).map(|collection| collection.key))
+}
+
+// Reads an Option<T> field that may be missing altogether at the end of the account
+fn deserialize_trailing<T: BorshDeserialize>(buf: &mut &[u8]) -> Result<Option<T>, ProgramError> {
+    if buf.is_empty() {
+        return Ok(None);
+    }
+    Option::<T>::deserialize(buf).map_err(|_| EscrowError::InvalidMetadataAccount.into())
+}
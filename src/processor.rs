@@ -1,21 +1,31 @@
 // Where the magic happens
 use solana_program::{
     account_info::{next_account_info, AccountInfo},
-    entrypoint::ProgramResult,
+    entrypoint::{ProgramResult, MAX_PERMITTED_DATA_INCREASE},
     msg,
     program::{invoke, invoke_signed},
     program_error::ProgramError,
     program_pack::{Pack, IsInitialized},
     pubkey::Pubkey,
-    sysvar::{rent::Rent, Sysvar},
+    system_instruction,
+    sysvar::{clock::Clock, rent::Rent, Sysvar},
 };
 
 
-use spl_token::state::Account as TokenAccount;
-
 // NOTE use crate -> refers to our local modules (crates?) we've made
 // All crates must be registered inside Cargo.toml
-use crate::{instruction::EscrowInstruction, error::EscrowError, state::Escrow};
+use crate::{
+    instruction::{EscrowInstruction, NativeSide},
+    error::EscrowError,
+    merkle, metadata,
+    state::{AssetKind, BasketLeg, Config, Escrow, LegacyEscrow, TokenProgram},
+    token,
+    validation::{
+        assert_address, assert_owned_by, assert_program, assert_signer, assert_token_program,
+        assert_upgrade_authority, assert_writable, load_config, load_escrow, unpack_mint, unpack_supported_mint,
+        unpack_token_account,
+    },
+};
 
 pub struct Processor;
 // Q: Is impl like making a class?
@@ -37,21 +47,72 @@ impl Processor {
         // since we don't have anything). msg! logs where we are going.
         match instruction {
             // tag = 0, we run the InitEscrow processing function
-            EscrowInstruction::InitEscrow { amount } => {
+            // NOTE While the program is paused, nobody can open new escrows or take existing
+            // ones. Cancel and RefundExpired keep working so initializers can always get their
+            // funds back.
+            EscrowInstruction::InitEscrow { amount, offered_amount, expires_at, native_side, nonce, allowed_taker, taker_merkle_root, basket_offered_amounts, basket_requested_amounts, offered_nft, offered_collection } => {
                 msg!("Instruction: InitEscrow");
-                Self::process_init_escrow(accounts, amount, program_id)
+                Self::assert_not_paused(accounts.get(8), program_id)?;
+                Self::process_init_escrow(accounts, amount, offered_amount, expires_at, native_side, nonce, allowed_taker, taker_merkle_root, &basket_offered_amounts, &basket_requested_amounts, offered_nft, offered_collection, program_id)
             },
             // tag = 1, we run the Exchange processing function 
-            EscrowInstruction::Exchange { amount } => {
+            EscrowInstruction::Exchange { amount, proof, use_associated_token_account } => {
                 msg!("Instruction: Exchange");
-                Self::process_exchange(accounts, amount, program_id)
+                Self::assert_not_paused(accounts.get(9), program_id)?;
+                Self::process_exchange(accounts, amount, &proof, use_associated_token_account, program_id)
+            },
+            // tag = 2, we run the Cancel processing function
+            EscrowInstruction::Cancel { use_associated_token_account } => {
+                msg!("Instruction: Cancel");
+                Self::process_cancel(accounts, use_associated_token_account, program_id)
+            },
+            // tag = 3, we run the RefundExpired processing function
+            EscrowInstruction::RefundExpired { use_associated_token_account } => {
+                msg!("Instruction: RefundExpired");
+                Self::process_refund_expired(accounts, use_associated_token_account, program_id)
+            },
+            // tag = 4, we run the InitConfig processing function
+            EscrowInstruction::InitConfig { fee_bps, treasury } => {
+                msg!("Instruction: InitConfig");
+                Self::process_init_config(accounts, fee_bps, treasury, program_id)
+            },
+            // tag = 5, we run the UpdateConfig processing function
+            EscrowInstruction::UpdateConfig { fee_bps, treasury } => {
+                msg!("Instruction: UpdateConfig");
+                Self::process_update_config(accounts, fee_bps, treasury, program_id)
+            },
+            // tag = 6, we run the TransferAdmin processing function
+            EscrowInstruction::TransferAdmin { new_admin } => {
+                msg!("Instruction: TransferAdmin");
+                Self::process_transfer_admin(accounts, new_admin, program_id)
+            },
+            // tag = 7, we run the SetPaused processing function
+            EscrowInstruction::SetPaused { paused } => {
+                msg!("Instruction: SetPaused");
+                Self::process_set_paused(accounts, paused, program_id)
+            },
+            // tag = 8, we run the MigrateEscrow processing function
+            EscrowInstruction::MigrateEscrow => {
+                msg!("Instruction: MigrateEscrow");
+                Self::process_migrate_escrow(accounts, program_id)
             }
         }
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn process_init_escrow(
         accounts: &[AccountInfo],
         amount: u64,
+        offered_amount: u64,
+        expires_at: Option<i64>,
+        native_side: NativeSide,
+        nonce: u64,
+        allowed_taker: Option<Pubkey>,
+        taker_merkle_root: Option<[u8; 32]>,
+        basket_offered_amounts: &[u64],
+        basket_requested_amounts: &[u64],
+        offered_nft: bool,
+        offered_collection: Option<Pubkey>,
         program_id: &Pubkey,
     ) -> ProgramResult {
         // Create an mutable iterator
@@ -59,64 +120,117 @@ impl Processor {
         // IMPORTANT: The first account we expect - AS DEFINED IN instruction.rs -
         // is the escrow's initializer, i.e., Alice's MAIN ACCOUNT.
         // Recall the accounts defined in instruction.rs:
-        /// 0. `[signer]` The account of the person initializing the escrow
-        /// 1. `[writable]` Temporary token account that should be created prior to this instruction and owned by the initializer
-        /// 2. `[]` The initializer's token account for the token they will receive should the trade go through
-        /// 3. `[writable]` The escrow account, it will hold all necessary info about the trade.
-        /// 4. `[]` The rent sysvar. NOTE sysvar can be accessed without passing into entrypoint as an account
-        /// 5. `[]` The token program
+        // 0. `[signer, writable]` The account of the person initializing the escrow
+        // 1. `[writable]` The initializer's token account holding the tokens they offer (ignored when offering native SOL)
+        // 2. `[writable]` The vault the program creates to hold the offered tokens (or lamports)
+        // 3. `[]` The initializer's token account for the token they will receive should the trade go through
+        // 4. `[writable]` The escrow account PDA, created by this instruction. It will hold all necessary info about the trade.
+        // 5. `[]` The rent sysvar. NOTE sysvar can be accessed without passing into entrypoint as an account
+        // 6. `[]` The token program of the offered token
+        // 7. `[]` The system program
+        // 8. `[]` The program's config account
+        // 9. `[]` The mint of the offered token (not passed in when offering native SOL)
+        // 10. `[]` The offered NFT's metadata account (only when checking its collection)
+        // 11. `[]` The mint of the requested token (not passed in when requesting native SOL)
+        // Then (sending token account, vault, mint) for every extra offered token of a basket
+        // trade, followed by (receiving token account, mint) for every extra requested token
 
         let initializer = next_account_info(account_info_iter)?;
 
-        // Check that Alice is the signer via AccountInfo is_signer boolean field
-        if !initializer.is_signer {
-            return Err(ProgramError::MissingRequiredSignature);
-        }
+        // Check that Alice is the signer via AccountInfo is_signer boolean field.
+        // She also pays for the accounts we create, so she must be writable too.
+        assert_signer(initializer)?;
+        assert_writable(initializer)?;
 
-        // Grab the temporary token account that will eventually hold the transferred
-        // tokens. NOTE It needs to be writable but we don't need to explicitly check,
-        // because the transaction will fail automatically should Alice (initializer)
-        // not mark the account as writable.
+        // Grab Alice's token account holding the X tokens she's offering. The program
+        // will move them into the vault itself, so Alice no longer has to create and
+        // fund a temp token account in a separate instruction.
+        let initializers_sending_token_account = next_account_info(account_info_iter)?;
+
+        // Grab the vault that will hold the offered tokens until the trade goes through.
+        // NOTE The transaction would fail anyway should Alice (initializer) not mark the
+        // account as writable, but checking it upfront gives her a much clearer error.
         let temp_token_account = next_account_info(account_info_iter)?;
+        assert_writable(temp_token_account)?;
+        // NOTE When Alice requests native SOL she passes her main account here instead,
+        // since that's where Bob's lamports will go.
         let token_to_receive_account = next_account_info(account_info_iter)?;
 
-        // IMPORTANT:
-        // Q: Why check token_to_receive_account.owner is actually owned by Token Program,
-        // but don't do the same for temp_token_account?
-        // A: Because later on in the function we will ask the Token Program to transfer
-        // ownership of the temp_token_account to the PDA (Program Derived Address).
-        // This transfer will fail if the temp_token_account is not owned by the Token Program,
-        // because only PROGRAMS that own accounts may change accounts. Hence, there is no
-        // need to add another check here.
-        // We don't make any changes to the token_to_receive_account though (inside Alice's
-        // transaction). We will just save it into the escrow data so that when Bob takes the
-        // trade, the escrow will know where to send his asset Y. Thus, for this account, 
-        // we should add a check.
-        // NOTE Nothing terrible would happen if we didn't add this check. Instead, Bob's
-        // transaction would fail because the Token Program will attempt to send the Y tokens
-        // to Alice but not be the owner of the token_to_receive_account. That said, it seems
-        // more reasonable explicitly specify which transaction failed/led to the invalid state.
-        // NOTE spl_token is a crate and it's aka the token program
-        if *token_to_receive_account.owner != spl_token::id() {
-            // NOTE '*' means the ACTUAL value, NOT a reference. * is for de-referencing.
-            return Err(ProgramError::IncorrectProgramId);
-        }
-        // TODO Check that token_to_receive_account is a TOKEN account, NOT a 
-        // token MINT account!
-        // if *token_to_receive_account.type != spl_token::
-
+        // The escrow (state) account is a PDA seeded with Alice's key and a nonce she picks,
+        // so anybody can find her escrows by derivation and she can open as many as she likes.
         let escrow_account = next_account_info(account_info_iter)?;
-        let rent = &Rent::from_account_info(next_account_info(account_info_iter)?)?;
+        assert_writable(escrow_account)?;
+        let nonce_bytes = nonce.to_le_bytes();
+        let (escrow_address, escrow_bump_seed) = Pubkey::find_program_address(
+            &[b"escrow", initializer.key.as_ref(), &nonce_bytes],
+            program_id,
+        );
+        assert_address(escrow_account, &escrow_address, EscrowError::InvalidEscrowAddress)?;
+        let escrow_signer_seeds: &[&[u8]] = &[&b"escrow"[..], initializer.key.as_ref(), &nonce_bytes, &[escrow_bump_seed]];
+
+        // A basket trade swaps extra tokens on top of the first ones (e.g., three tokens for
+        // one). Each side can hold at most MAX_BASKET_LEGS extra tokens, since the escrow
+        // account has a fixed size. NOTE Baskets are tokens only, to keep Exchange simple.
+        let is_basket = !basket_offered_amounts.is_empty() || !basket_requested_amounts.is_empty();
+        if (is_basket && native_side != NativeSide::None)
+            || basket_offered_amounts.len() > Escrow::MAX_BASKET_LEGS
+            || basket_requested_amounts.len() > Escrow::MAX_BASKET_LEGS
+        {
+            return Err(EscrowError::InvalidBasket.into());
+        }
+
+        // An NFT is offered as exactly ONE unit of its mint, and only an NFT can be
+        // checked against a collection.
+        if (offered_nft && (native_side == NativeSide::Offered || offered_amount != 1))
+            || (offered_collection.is_some() && !offered_nft)
+        {
+            return Err(EscrowError::InvalidNft.into());
+        }
+
+        let rent_sysvar = next_account_info(account_info_iter)?;
+        let rent = &Rent::from_account_info(rent_sysvar)?;
+
+        // To create the escrow account and the vault, we will call the system program and
+        // the token program (spl_token) from our escrow program and create new INSTRUCTIONS!
+        // NOTE This is called Cross-Program Invocation/Instruction and executed using either
+        // invoke() or invoke_signed() functions.
+        // Grab the token_program and system_program accounts
+        // NOTE The program getting called through a CPI must be included as an account
+        // in the 2nd argument of invoke() and invoke_signed() functions.
+        // NOTE The token program is the one the offered tokens belong to, which is either
+        // SPL Token or Token-2022 (pass SPL Token when offering native SOL).
+        let token_program = next_account_info(account_info_iter)?;
+        let system_program = next_account_info(account_info_iter)?;
+        // NOTE Before making a CPI, we should check that we're calling the real program.
+        // Otherwise, we might be calling a rogue program (and handing it Alice's signature)!
+        // spl-token crate > 3.1.1 (which we're using) does this for the token program
+        // if we use their instruction builder functions, but we're about to make the
+        // token program the OWNER of the vault through the system program, so let's
+        // check both ourselves.
+        let offered_token_program = assert_token_program(token_program)?;
+        assert_program(system_program, &solana_program::system_program::id())?;
 
+        // Skip the config account. Processor::process already made sure we're not paused.
+        next_account_info(account_info_iter)?;
+
+        // Create the escrow account ourselves, so clients can't pass in a mis-sized or
+        // foreign-owned account. The PDA "signs" the system program CPI with its seeds.
         // NOTE Most times you want your accounts to be rent-exempt, because if
         // balances go to zero, they DISAPPEAR (i.e., purged from memory at runtime)!
-        // This is why we're checking whether escrow (state) account is exempt. 
-        // If we didn't do this check, and Alice were to pass in a non-rent-exempt account,
-        // the account balance might go to zero balance before Bob takes the trade.
-        // With the account gone, Alice would have no way to recover her tokens.
-        if !rent.is_exempt(escrow_account.lamports(), escrow_account.data_len()) {
-            return Err(EscrowError::NotRentExempt.into());
-        }
+        // If the escrow (state) account weren't rent exempt, the account balance might go
+        // to zero before Bob takes the trade. With the account gone, Alice would have no
+        // way to recover her tokens. Hence we fund it with the rent exempt minimum.
+        // NOTE If the account already exists (e.g., Alice reused a nonce), this fails.
+        msg!("Calling the system program to create the escrow account...");
+        Self::create_pda_account(
+            initializer,
+            escrow_account,
+            rent.minimum_balance(Escrow::LEN),
+            Escrow::LEN,
+            program_id,
+            system_program,
+            escrow_signer_seeds,
+        )?;
 
         // NOTE First time we access the account data ([u8]). We deserialize/decode it with
         // Escrow::unpack_unchecked() from state.rs (soon to create), which will return
@@ -127,6 +241,87 @@ impl Processor {
             return Err(ProgramError::AccountAlreadyInitialized);
         }
 
+        // The vault will be owned by (i.e., its authority will be) the escrow's PDA
+        // NOTE We create a PDA by passing in an array of seeds and the program_id into the
+        // find_program_address function. We get back a new pda and bump_seed with a
+        // 1/(2^255) chance the function fails (2^255 is BIG).
+        // NOTE Every escrow gets its OWN PDA by seeding it with the escrow account's key
+        // (like the Associated Token Account Program does with non-static seeds). If we
+        // used a single static seed, one PDA would own the vaults of EVERY escrow, so a
+        // single bad check could drain all of them.
+        // NOTE We save the bump seed in the escrow state so Exchange and Cancel can
+        // recreate the PDA with the cheaper create_program_address() instead of
+        // searching for the bump with find_program_address() on every call.
+        // NOTE A PDA are public keys that are derived from the program_id and the seeds as
+        // well as having been pushed off the ed25519 standard eliptic curve by the 
+        // bump seed (nonce)! (Solana key pairs use the ed25519 standard.)
+        // Hence, PDAs do not lie on the ed25519 curve and therefore have no private keys
+        // associated with them! A PDA is just a random array of bytes with the only defining
+        // feature being that they are NOT on that curve. We'll later use the bump seed when
+        // we look into signing messages with PDAs even without a private key (Bob's tx)!
+        // https://paulx.dev/blog/2021/01/14/programming-on-solana-an-introduction/#pdas-part-2
+        let (pda, bump_seed) = Pubkey::find_program_address(&[b"escrow", escrow_account.key.as_ref()], program_id);
+
+        // The vault itself lives at another PDA, so anybody can derive its address from
+        // the escrow account. NOTE The vault's seeds are only needed to create it.
+        let (vault, vault_bump_seed) = Pubkey::find_program_address(&[b"vault", escrow_account.key.as_ref()], program_id);
+        assert_address(temp_token_account, &vault, EscrowError::InvalidVaultAddress)?;
+        let vault_signer_seeds: &[&[u8]] = &[&b"vault"[..], escrow_account.key.as_ref(), &[vault_bump_seed]];
+
+        // Figure out what Alice is offering. We save the amount so partial fills can be
+        // priced proportionally later on, along with its mint so Exchange knows which
+        // token Bob should receive.
+        if offered_amount == 0 {
+            return Err(ProgramError::InsufficientFunds);
+        }
+        let (offered_kind, offered_mint, offered_token_program) = match native_side {
+            NativeSide::Offered => (AssetKind::Native, Pubkey::default(), TokenProgram::SplToken),
+            NativeSide::None | NativeSide::Requested => {
+                assert_writable(initializers_sending_token_account)?;
+                let initializers_sending_token_account_info = unpack_token_account(initializers_sending_token_account, token_program.key)?;
+                let offered_kind = if offered_nft { AssetKind::Nft } else { AssetKind::Token };
+                (offered_kind, initializers_sending_token_account_info.mint, offered_token_program)
+            }
+        };
+
+        // Figure out what Alice is requesting.
+        let (requested_kind, requested_mint, requested_token_program) = match native_side {
+            NativeSide::Requested => {
+                // Bob's lamports go straight to Alice's main account
+                assert_address(token_to_receive_account, initializer.key, EscrowError::ReceiveAccountMismatch)?;
+                (AssetKind::Native, Pubkey::default(), TokenProgram::SplToken)
+            }
+            NativeSide::None | NativeSide::Offered => {
+                // IMPORTANT:
+                // Q: Why check token_to_receive_account.owner is actually owned by Token Program?
+                // A: For the token account Alice sends from, the Token Program transfer would fail
+                // anyway if the account were not owned by the Token Program, because only PROGRAMS
+                // that own accounts may change accounts (we still check it upfront for a clearer error).
+                // We don't make any changes to the token_to_receive_account though (inside Alice's
+                // transaction). We will just save it into the escrow data so that when Bob takes the
+                // trade, the escrow will know where to send his asset Y. Thus, for this account, 
+                // we should add a check.
+                // NOTE Nothing terrible would happen if we didn't add this check. Instead, Bob's
+                // transaction would fail because the Token Program will attempt to send the Y tokens
+                // to Alice but not be the owner of the token_to_receive_account. That said, it seems
+                // more reasonable explicitly specify which transaction failed/led to the invalid state.
+                // NOTE spl_token is a crate and it's aka the token program.
+                // unpack_token_account() checks the owner before unpacking it as a TokenAccount,
+                // which also makes sure it's a TOKEN account, NOT a token MINT account!
+                // We save its mint as the token Alice is requesting.
+                // NOTE The requested token may belong to a different token program than the
+                // offered one, so we find out which by looking at the account's owner.
+                let requested_token_program = TokenProgram::from_id(token_to_receive_account.owner)
+                    .ok_or(EscrowError::InvalidAccountOwner)?;
+                let token_to_receive_account_info = unpack_token_account(token_to_receive_account, &requested_token_program.id())?;
+                // Swapping a token for the same token makes no sense
+                if offered_kind != AssetKind::Native && token_to_receive_account_info.mint == offered_mint {
+                    return Err(EscrowError::RequestedMintMismatch.into());
+                }
+                (AssetKind::Token, token_to_receive_account_info.mint, requested_token_program)
+            }
+        };
+
         // Now let's add the state serialization. We've already created the Escrow struct
         // instance (via unpack_unchecked) and checked that it is indeed uninitialized.
         // Time to populate the Escrow struct fields!
@@ -137,78 +332,169 @@ impl Processor {
         escrow_info.temp_token_account_pubkey = *temp_token_account.key;
         escrow_info.initializer_token_to_receive_account_pubkey = *token_to_receive_account.key;
         escrow_info.expected_amount = amount;
+        escrow_info.expires_at = expires_at;
+        escrow_info.offered_amount = offered_amount;
+        escrow_info.remaining_amount = offered_amount;
+        escrow_info.offered_mint = offered_mint;
+        escrow_info.requested_mint = requested_mint;
+        escrow_info.offered_kind = offered_kind;
+        escrow_info.requested_kind = requested_kind;
+        escrow_info.bump_seed = bump_seed;
+        escrow_info.nonce = nonce;
+        escrow_info.allowed_taker = allowed_taker;
+        escrow_info.taker_merkle_root = taker_merkle_root;
+        escrow_info.offered_collection = offered_collection;
+        escrow_info.offered_token_program = offered_token_program;
+        escrow_info.requested_token_program = requested_token_program;
 
-        // Serialize our escrow_info object using 'pack' default function, which internally
-        // calls our 'pack_into_slice' function.
-        Escrow::pack(escrow_info, &mut escrow_account.data.borrow_mut())?;
+        // An escrow that is already expired could never be taken, and any crank could
+        // refund it right away. Make sure Alice picked an expiry in the future.
+        if escrow_info.is_expired(Clock::get()?.unix_timestamp) {
+            return Err(EscrowError::ExpiryInPast.into());
+        }
 
-        // Need to transfer (user space) ownership of the temporary token account to the PDA
-        // NOTE We create a PDA by passing in an array of seeds and the program_id into the
-        // find_program_address function. We get back a new pda and bump_seed (we won't need
-        // the bump seed in Alice's tx) with a 1/(2^255) chance the function fails (2^255 is BIG).
-        // In our case the seeds can be static (there are cases such as Associated Token
-        // Account Program where seeds aren't static). We just need 1 PDA that can own N temp
-        // token accounts for different escrows occurring at any and possibly the same point
-        // in time.
-        // NOTE A PDA are public keys that are derived from the program_id and the seeds as
-        // well as having been pushed off the ed25519 standard eliptic curve by the 
-        // bump seed (nonce)! (Solana key pairs use the ed25519 standard.)
-        // Hence, PDAs do not lie on the ed25519 curve and therefore have no private keys
-        // associated with them! A PDA is just a random array of bytes with the only defining
-        // feature being that they are NOT on that curve. We'll later use the bump seed when
-        // we look into signing messages with PDAs even without a private key (Bob's tx)!
-        // https://paulx.dev/blog/2021/01/14/programming-on-solana-an-introduction/#pdas-part-2
-        let (pda, _bump_seed) = Pubkey::find_program_address(&[b"escrow"], program_id);
+        if offered_kind == AssetKind::Native {
+            // Instead of a token account, the vault is created as an account owned by THIS
+            // program and Alice's lamports are moved into it. That way the program can later
+            // debit the lamports directly, without calling any other program.
+            // NOTE The vault needs to stay rent exempt on top of the lamports Alice offers.
+            let vault_lamports = rent
+                .minimum_balance(0)
+                .checked_add(offered_amount)
+                .ok_or(EscrowError::AmountOverflow)?;
+            msg!("Calling the system program to move lamports into the escrow's vault...");
+            Self::create_pda_account(
+                initializer,
+                temp_token_account,
+                vault_lamports,
+                0,
+                program_id,
+                system_program,
+                vault_signer_seeds,
+            )?;
+        } else {
+            let offered_mint_account = next_account_info(account_info_iter)?;
+            assert_address(offered_mint_account, &offered_mint, EscrowError::OfferedMintMismatch)?;
+            // Save the offered token's decimals, which every transfer_checked gets from now on
+            let offered_mint_info = unpack_supported_mint(offered_mint_account, token_program.key)?;
+            escrow_info.offered_decimals = offered_mint_info.decimals;
 
-        // To transfer ownership of temporary token account to PDA, we will call the
-        // token program (spl_token) from our escrow program and create a new
-        // INSTRUCTION! owner_change_ix is an Instruction!
-        // NOTE This is called Cross-Program Invocation/Instruction and executed using either
-        // invoke() or invoke_signed() functions.
-        // Grab the token_program account
-        // NOTE The program getting called through a CPI must be included as an account
-        // in the 2nd argument of invoke() and invoke_signed() functions.
-        let token_program = next_account_info(account_info_iter)?;
-        // Create the instruction (CPI) that the token_program would expect were we executing
-        // a normal call. The token program defines some helper functions inside its
-        // instruction.rs that we can make use of (e.g., set_authority fn). 
-        // set_authority() is a builder function to create such an instruction.
-        // NOTE We're using the Signature Extension concept here:
-        // https://docs.solana.com/developing/programming-model/calling-between-programs#instructions-that-require-privileges
-        // "When including a signed account in a program call, in all CPIs including that
-        // account made by the program inside the current instruction, the account will
-        // also be signed, i.e., the signature is extended to the CPIs."
-        // NOTE This means that since Alice signed the InitEscrow transaction, the program
-        // can make the token program set_authority CPI and include her pubkey as a signer pubkey.
-        // This is necessary because changing a token account's owner should of course require
-        // the approval of the current owner.
-        // IMPORTANT By token program we mean the spl_token program, which has its own
-        // instruction.rs:
-        // https://docs.rs/spl-token/2.0.4/src/spl_token/instruction.rs.html#538-550
-        // NOTE Generally, before making a CPI, we should check that token_program is truly
-        // the account of the token program. Otherwise, we might be calling a rogue program.
-        // Thankfully, spl-token crate > 3.1.1 (which we're using) does this if we use
-        // their instruction builder functions (i.e., spl_token::instruction::set_authority())
-        let owner_change_ix = spl_token::instruction::set_authority(
-            token_program.key, // token program id
-            temp_token_account.key, // account whose authority we want to change
-            Some(&pda), // account that's the new authority (PDA)
-            spl_token::instruction::AuthorityType::AccountOwner, // type of authority change
-            initializer.key,  // current account owner (Alice -> initializer.key)
-            &[&initializer.key], // public keys signing the CPI
-        )?;
+            if offered_kind == AssetKind::Nft {
+                // Make sure Alice really offers an NFT: a mint with a supply of exactly one
+                // token that can't be split (0 decimals). Otherwise Bob could end up with
+                // one of many (or a fraction of a) "NFT".
+                // NOTE The mint authority must be gone as well (Metaplex hands it to the
+                // master edition), or Alice could mint a second copy after the trade.
+                if offered_mint_info.supply != 1
+                    || offered_mint_info.decimals != 0
+                    || offered_mint_info.mint_authority.is_some()
+                {
+                    return Err(EscrowError::InvalidNft.into());
+                }
+                // 10. If Alice promises the NFT is part of a collection, check it against the
+                // NFT's metadata. Only a collection that VERIFIED the NFT counts.
+                if let Some(offered_collection) = offered_collection {
+                    let offered_metadata_account = next_account_info(account_info_iter)?;
+                    if metadata::verified_collection(offered_metadata_account, &offered_mint)? != Some(offered_collection) {
+                        return Err(EscrowError::CollectionMismatch.into());
+                    }
+                }
+            }
 
-        msg!("Calling the token program to transfer token account ownership...");
-        invoke(
-            &owner_change_ix, // The Cross-Program Instruction
-            &[
-                temp_token_account.clone(), // Accounts required by the CPI instruction
-                initializer.clone(), // Accounts required by the CPI instruction
-                token_program.clone(), // Account of the program we're calling
-                // NOTE We can see params of spl_token set_authority() in its instruction.rs
-                // https://docs.rs/spl-token/2.0.4/src/spl_token/instruction.rs.html#538-550
-            ], 
-        )?;
+            Self::open_token_vault(
+                initializer,
+                initializers_sending_token_account,
+                temp_token_account,
+                offered_mint_account,
+                &pda,
+                offered_amount,
+                escrow_info.offered_decimals,
+                rent,
+                rent_sysvar,
+                token_program,
+                system_program,
+                vault_signer_seeds,
+            )?;
+        }
+
+        // 11. Grab the mint of the requested token to save its decimals as well
+        if requested_kind == AssetKind::Token {
+            let requested_mint_account = next_account_info(account_info_iter)?;
+            assert_address(requested_mint_account, &requested_mint, EscrowError::RequestedMintMismatch)?;
+            escrow_info.requested_decimals = unpack_supported_mint(requested_mint_account, &requested_token_program.id())?.decimals;
+        }
+
+        // Every extra token Alice offers in a basket trade gets its own vault (also owned
+        // by the PDA), seeded with its mint so anybody can derive it from the escrow account.
+        // NOTE All tokens on one side of a trade belong to the same token program.
+        for &leg_amount in basket_offered_amounts {
+            let leg_sending_token_account = next_account_info(account_info_iter)?;
+            let leg_vault = next_account_info(account_info_iter)?;
+            let leg_mint_account = next_account_info(account_info_iter)?;
+            if leg_amount == 0 {
+                return Err(ProgramError::InsufficientFunds);
+            }
+            assert_writable(leg_sending_token_account)?;
+            assert_writable(leg_vault)?;
+            let leg_mint = unpack_token_account(leg_sending_token_account, token_program.key)?.mint;
+            assert_address(leg_mint_account, &leg_mint, EscrowError::OfferedMintMismatch)?;
+            let leg_decimals = unpack_supported_mint(leg_mint_account, token_program.key)?.decimals;
+            let (leg_vault_address, leg_vault_bump_seed) = Pubkey::find_program_address(
+                &[b"vault", escrow_account.key.as_ref(), leg_mint.as_ref()],
+                program_id,
+            );
+            assert_address(leg_vault, &leg_vault_address, EscrowError::InvalidVaultAddress)?;
+
+            Self::open_token_vault(
+                initializer,
+                leg_sending_token_account,
+                leg_vault,
+                leg_mint_account,
+                &pda,
+                leg_amount,
+                leg_decimals,
+                rent,
+                rent_sysvar,
+                token_program,
+                system_program,
+                &[&b"vault"[..], escrow_account.key.as_ref(), leg_mint.as_ref(), &[leg_vault_bump_seed]],
+            )?;
+            escrow_info.basket_offered.push(BasketLeg {
+                mint: leg_mint,
+                amount: leg_amount,
+                account: *leg_vault.key,
+            });
+            escrow_info.basket_offered_decimals.push(leg_decimals);
+        }
+
+        // Save where the extra tokens Alice requests should go, just like the first one.
+        for &leg_amount in basket_requested_amounts {
+            let leg_token_to_receive_account = next_account_info(account_info_iter)?;
+            let leg_mint_account = next_account_info(account_info_iter)?;
+            if leg_amount == 0 {
+                return Err(EscrowError::InvalidBasket.into());
+            }
+            let leg_mint = unpack_token_account(leg_token_to_receive_account, &requested_token_program.id())?.mint;
+            assert_address(leg_mint_account, &leg_mint, EscrowError::RequestedMintMismatch)?;
+            escrow_info.basket_requested.push(BasketLeg {
+                mint: leg_mint,
+                amount: leg_amount,
+                account: *leg_token_to_receive_account.key,
+            });
+            escrow_info
+                .basket_requested_decimals
+                .push(unpack_supported_mint(leg_mint_account, &requested_token_program.id())?.decimals);
+        }
+
+        // Every token may only show up once in a trade, otherwise Exchange couldn't tell
+        // the legs apart (and swapping a token for itself makes no sense anyway).
+        if escrow_info.is_basket() && !escrow_info.has_unique_mints() {
+            return Err(EscrowError::InvalidBasket.into());
+        }
+
+        // Serialize our escrow_info object using 'pack' default function, which internally
+        // calls our 'pack_into_slice' function.
+        Escrow::pack(escrow_info, &mut escrow_account.data.borrow_mut())?;
 
         Ok(())
     }
@@ -216,90 +502,183 @@ impl Processor {
 
     fn process_exchange(
         accounts: &[AccountInfo],
-        amount_expected_by_taker: u64,
+        fill_amount: u64,
+        proof: &[[u8; 32]],
+        use_associated_token_account: bool,
         program_id: &Pubkey,
     ) -> ProgramResult {
         msg!("Calling process_exchange function");
         // Get an iterator from the accounts passed into the Exchange instruction
         let account_info_iter = &mut accounts.iter();
-        /// IMPORTANT: This is Bob's Transaction. Alice has already created the Escrow,
-        /// so now Bob needs to send the correct amount of Y tokens to the Escrow,
-        /// then the Escrow will send him Alice's X tokens and Alice his Y tokens.
-        ///
-        ///
-        /// 0. `[signer]` The account of the person taking the trade (Bob. Alice is the Initializer)
-        /// 1. `[writable]` The taker's (Bob) token account for the token they send 
-        /// 2. `[writable]` The taker's token account for the token they will receive should the trade go through
-        /// 3. `[writable]` The PDA's temp token account to get tokens from and eventually close
-        /// 4. `[writable]` The initializer's main account to send their rent fees to
-        /// 5. `[writable]` The initializer's token account that will receive tokens
-        /// 6. `[writable]` The escrow account holding the escrow info
-        /// 7. `[]` The token program
-        /// 8. `[]` The PDA account
+        // IMPORTANT: This is Bob's Transaction. Alice has already created the Escrow,
+        // so now Bob needs to send the correct amount of Y tokens to the Escrow,
+        // then the Escrow will send him Alice's X tokens and Alice his Y tokens.
+        //
+        //
+        // 0. `[signer]` The account of the person taking the trade (Bob. Alice is the Initializer)
+        //    (also writable when use_associated_token_account is set, since Bob pays for his new token accounts)
+        // 1. `[writable]` The taker's (Bob) token account for the token they send 
+        // 2. `[writable]` The taker's token account for the token they will receive should the trade go through
+        //    (his associated token account, created if needed, when use_associated_token_account is set)
+        // 3. `[writable]` The escrow's vault (the PDA's temp token account) to get tokens from and eventually close
+        // 4. `[writable]` The initializer's main account to send their rent fees to
+        // 5. `[writable]` The initializer's token account that will receive tokens
+        // 6. `[writable]` The escrow account holding the escrow info
+        // 7. `[]` The token program of the offered token
+        // 8. `[]` The PDA account
+        // 9. `[]` The program's config account
+        // 10. `[writable]` The treasury's account that receives the protocol fee
+        // 11. `[]` The system program (only used when native SOL was requested)
+        // 12. `[]` The token program of the requested token
+        // 13. `[]` The mint of the offered token, `[writable]` with Token-2022 (not passed in when native SOL was offered)
+        // 14. `[]` The mint of the requested token (not passed in when native SOL was requested)
+        // 15. `[]` The associated token account program (only passed in when use_associated_token_account is set)
+        // 16. onwards: the accounts of the extra tokens of a basket trade (see settle_basket)
         // Time to loop over the accounts and assign to variables
         // 0. Let's grab the taker account information
         let taker = next_account_info(account_info_iter)?;
         // Check that taker (Bob) is signer via AccountInfo is_signer boolean field
-        if !taker.is_signer {
-            return Err(ProgramError::MissingRequiredSignature);
+        assert_signer(taker)?;
+        // Bob pays the rent of any associated token account we create for him
+        if use_associated_token_account {
+            assert_writable(taker)?;
         }
 
         // 1. Grab taker's sending token account (Y token)
         let takers_sending_token_account = next_account_info(account_info_iter)?;
+        assert_writable(takers_sending_token_account)?;
 
         // 2. Grab taker's receiving token account (X token) if trade is successful
         let takers_receiving_token_account = next_account_info(account_info_iter)?;
+        assert_writable(takers_receiving_token_account)?;
 
         // 3. Grab Alice's temp X token account that's currently owned by
-        // the Escrow Program's PDA
+        // the Escrow Program's PDA (or the vault holding her lamports when she offered native SOL)
         let pdas_temp_token_account = next_account_info(account_info_iter)?;
-        // Check that this temp account holds what taker (Bob) expects in X token
-        // Q: The 'amount' information is stored in the account's data I think...
-        // A: Yes, had the right idea. We're going to use TokenAccount from state.rs
-        // to help us unpack this account data to double-check amounts are accurate
-        let pdas_temp_token_account_info = TokenAccount::unpack(&pdas_temp_token_account.data.borrow())?;
-        // Find the PDA address by using array of seeds and program_id.
-        // NOTE We're going to use this pda when passing authority_pubkey in transfer ix
-        let (pda, bump_seed) = Pubkey::find_program_address(&[b"escrow"], program_id);
-        // Finally, check that temp X token account amount is equal to this process_exchange's
-        // amount_expected_by_taker value.
-        if amount_expected_by_taker != pdas_temp_token_account_info.amount {
-            // Q: What does .into() do?
-            return Err(EscrowError::ExpectedAmountMismatch.into());
-        }
+        assert_writable(pdas_temp_token_account)?;
 
         // 4. Grab the initializer's main account information
         let initializers_main_account = next_account_info(account_info_iter)?;
+        assert_writable(initializers_main_account)?;
 
         // 5. Grab initializer's Y token account that will receive Y tokens (Alice's Y token)
         let initializers_token_to_receive_account = next_account_info(account_info_iter)?;
+        assert_writable(initializers_token_to_receive_account)?;
 
         // 6. Grab the Escrow State Account that's holding all the escrow info
+        // IMPORTANT: Make sure it's owned by our program before trusting its data!
+        // Otherwise Bob could pass in an account of his own with made-up escrow info
+        // (e.g., a vault he's not entitled to and a price of zero).
         let escrow_account = next_account_info(account_info_iter)?;
+        let mut escrow_info = load_escrow(escrow_account, program_id)?;
 
         // 6.1 Check that PDA's temp token account matches the same as the Escrow Account's
-        // temp_token_account key. First need to unpack escrow_account data
-        let escrow_info = Escrow::unpack(&escrow_account.data.borrow())?;
-        // NOTE Need to dereference the borrow using '*' to match struct Pubkey
-        if escrow_info.temp_token_account_pubkey != *pdas_temp_token_account.key {
-            return Err(ProgramError::InvalidAccountData);
-        }
+        // temp_token_account key.
+        assert_address(pdas_temp_token_account, &escrow_info.temp_token_account_pubkey, EscrowError::InvalidVaultAddress)?;
 
         // 6.2 Check whether Escrow Accounts data/info for initializer pubkey matches
         // the initializers_main_account that was passed into accounts arg
-        // NOTE Need to dereference the borrow using '*' to match struct Pubkey
-        if escrow_info.initializer_pubkey != *initializers_main_account.key {
-            return Err(ProgramError::InvalidAccountData);
-        }
+        assert_address(initializers_main_account, &escrow_info.initializer_pubkey, EscrowError::InitializerMismatch)?;
 
         // 6.3 Check whether the accounts for tokens to receive match between the
         // Escrow Account's data/info and the accounts arg. Basically checking
         // whether they both point to Alice's Y token account address.
-        if escrow_info.initializer_token_to_receive_account_pubkey != *initializers_token_to_receive_account.key {
-            return Err(ProgramError::InvalidAccountData);
+        assert_address(
+            initializers_token_to_receive_account,
+            &escrow_info.initializer_token_to_receive_account_pubkey,
+            EscrowError::ReceiveAccountMismatch,
+        )?;
+
+        // 6.4 If Alice negotiated the trade with a specific taker, only that taker may take it
+        if let Some(allowed_taker) = escrow_info.allowed_taker {
+            if allowed_taker != *taker.key {
+                return Err(EscrowError::UnauthorizedTaker.into());
+            }
+        }
+        // ...and if she set up an allowlist, Bob must prove he's on it (before ANY transfer happens)
+        if let Some(taker_merkle_root) = escrow_info.taker_merkle_root {
+            if !merkle::verify_taker(proof, &taker_merkle_root, taker.key) {
+                return Err(EscrowError::InvalidMerkleProof.into());
+            }
+        }
+
+        // 6.5 Check that the trade hasn't expired yet. We read the current time from the
+        // Clock sysvar. NOTE Like rent, sysvars can be fetched via Clock::get() without
+        // being passed in as an account.
+        let clock = Clock::get()?;
+        if escrow_info.is_expired(clock.unix_timestamp) {
+            return Err(EscrowError::EscrowExpired.into());
         }
 
-        // 7. Grab the Token Program account
+        // 6.6 Check that all four token accounts hold the tokens the escrow was set up
+        // for. Otherwise we'd be relying on the token program to fail the transfers.
+        // Bob sends and Alice receives the requested Y token...
+        // NOTE unpack_token_account() also checks the accounts belong to the token program
+        // the escrow saved for each side.
+        if escrow_info.requested_kind == AssetKind::Token {
+            let requested_token_program_id = escrow_info.requested_token_program.id();
+            let takers_sending_token_account_info = unpack_token_account(takers_sending_token_account, &requested_token_program_id)?;
+            let initializers_token_to_receive_account_info = unpack_token_account(initializers_token_to_receive_account, &requested_token_program_id)?;
+            if takers_sending_token_account_info.mint != escrow_info.requested_mint
+                || initializers_token_to_receive_account_info.mint != escrow_info.requested_mint
+            {
+                return Err(EscrowError::RequestedMintMismatch.into());
+            }
+        } else if *takers_sending_token_account.key != *taker.key {
+            // NOTE When Alice requested native SOL, Bob pays straight from his main account
+            // (and we already checked above that Alice receives in her main account).
+            return Err(EscrowError::TakerAccountMismatch.into());
+        }
+        // ...while the PDA's temp account sends and Bob receives the offered X token.
+        // Q: The 'amount' information is stored in the account's data I think...
+        // A: Yes, had the right idea. We're going to use TokenAccount from state.rs
+        // to help us unpack this account data to double-check amounts are accurate
+        // NOTE When Alice offered native SOL, the lamports sit in the vault and Bob can
+        // receive them in whichever account he likes.
+        let pdas_temp_token_account_amount = if escrow_info.offered_kind != AssetKind::Native {
+            let offered_token_program_id = escrow_info.offered_token_program.id();
+            let pdas_temp_token_account_info = unpack_token_account(pdas_temp_token_account, &offered_token_program_id)?;
+            if pdas_temp_token_account_info.mint != escrow_info.offered_mint {
+                return Err(EscrowError::OfferedMintMismatch.into());
+            }
+            // NOTE Bob's associated token account may not exist yet. Instead, we check it's
+            // the canonical one when we create it (see step 15).
+            if !use_associated_token_account
+                && unpack_token_account(takers_receiving_token_account, &offered_token_program_id)?.mint != escrow_info.offered_mint
+            {
+                return Err(EscrowError::OfferedMintMismatch.into());
+            }
+            pdas_temp_token_account_info.amount
+        } else {
+            // The lamports vault must be the one our program created in InitEscrow
+            assert_owned_by(pdas_temp_token_account, program_id)?;
+            escrow_info.remaining_amount
+        };
+
+        // 6.7 Check that Bob isn't trying to take more X tokens than are left in the escrow
+        // (or zero tokens, which would be a pointless trade).
+        if fill_amount == 0 || fill_amount > escrow_info.remaining_amount {
+            // Q: What does .into() do?
+            return Err(EscrowError::ExpectedAmountMismatch.into());
+        }
+        // NOTE A basket trade can only be taken as a whole. Splitting every extra token
+        // proportionally would leave dust behind in the vaults.
+        if escrow_info.is_basket() && fill_amount != escrow_info.remaining_amount {
+            return Err(EscrowError::BasketPartialFill.into());
+        }
+        // Work out how many Y tokens Bob owes Alice for his share of the offer. The price
+        // is fixed by InitEscrow: expected_amount Y for offered_amount X.
+        let payment_amount = escrow_info
+            .payment_for_fill(fill_amount)
+            .ok_or(EscrowError::AmountOverflow)?;
+        // Remember what the earlier fills paid, so the fee can be charged on the running total
+        let paid_before = escrow_info
+            .paid_amount()
+            .ok_or(EscrowError::AmountOverflow)?;
+        escrow_info.remaining_amount -= fill_amount;
+        let is_fully_filled = escrow_info.remaining_amount == 0;
+
+        // 7. Grab the Token Program account of the offered X token
         // NOTE Recall that even programs in Solana live inside an account, i.e., the program
         // in its binary form (e.g., helloworld.so, spl_token.so, etc.) is actually going to
         // be the data of some account. This is key!
@@ -308,50 +687,240 @@ impl Processor {
         // Q: Check whether token_program account is the same as the program that initialized
         // the temp X token account? (NOT the user space owner property, as that is the Escrow
         // Program's PDA. Not sure if that's needed...)
+        // A: Yes. We're about to hand Bob's signature to it, so it better be the real one.
+        // It must be the token program the escrow saved for the offered side.
+        assert_program(token_program, &escrow_info.offered_token_program.id())?;
+
+        // 8. Grab the PDA account
+        // NOTE The PDA has authority on the temp X token account (the escrow's vault)
+        let pda_account = next_account_info(account_info_iter)?;
+        // Recreate the escrow's PDA from its seeds and the bump saved during InitEscrow.
+        // NOTE We're going to use this pda when passing authority_pubkey in transfer ix
+        let pda = Self::escrow_pda(escrow_account.key, escrow_info.bump_seed, program_id)?;
+        assert_address(pda_account, &pda, EscrowError::InvalidPdaAccount)?;
+        let pda_signer_seeds: &[&[u8]] = &[&b"escrow"[..], escrow_account.key.as_ref(), &[escrow_info.bump_seed]];
+
+        // 9. Grab the program's config account holding the protocol fee settings
+        let config_account = next_account_info(account_info_iter)?;
+        let config = load_config(config_account, program_id)?;
+
+        // 10. Grab the treasury's account that collects the fee. It must belong to the
+        // treasury and hold the requested Y token (or BE the treasury for native SOL).
+        let fee_recipient_account = next_account_info(account_info_iter)?;
+        assert_writable(fee_recipient_account)?;
+        if escrow_info.requested_kind == AssetKind::Native {
+            assert_address(fee_recipient_account, &config.treasury, EscrowError::FeeRecipientMismatch)?;
+        } else {
+            let fee_recipient_account_info = unpack_token_account(fee_recipient_account, &escrow_info.requested_token_program.id())?;
+            if fee_recipient_account_info.owner != config.treasury
+                || fee_recipient_account_info.mint != escrow_info.requested_mint
+            {
+                return Err(EscrowError::FeeRecipientMismatch.into());
+            }
+        }
+
+        // Split Bob's payment: the protocol fee goes to the treasury first and Alice
+        // receives the remainder.
+        let fee_amount = config
+            .fee_for_payment(paid_before, payment_amount)
+            .ok_or(EscrowError::AmountOverflow)?;
+        let initializer_amount = payment_amount
+            .checked_sub(fee_amount)
+            .ok_or(EscrowError::AmountOverflow)?;
+
+        // 11. Grab the system program. Bob's main account is owned by the system program,
+        // so only it can debit Bob's lamports (again thanks to Signature Extension).
+        let system_program = next_account_info(account_info_iter)?;
+        assert_program(system_program, &solana_program::system_program::id())?;
+
+        // 12. Grab the Token Program account of the requested Y token, which may differ from
+        // the offered X token's (e.g., SPL Token for X and Token-2022 for Y).
+        let requested_token_program = next_account_info(account_info_iter)?;
+        assert_program(requested_token_program, &escrow_info.requested_token_program.id())?;
+
+        // 13. Grab the mint of the offered X token (unless Alice offered native SOL)
+        let offered_mint_account = Self::next_offered_mint_account(&escrow_info, account_info_iter)?;
+
+        // 14. Grab the mint of the requested Y token (unless Alice requested native SOL)
+        let requested_mint_account = if escrow_info.requested_kind != AssetKind::Native {
+            let requested_mint_account = next_account_info(account_info_iter)?;
+            assert_address(requested_mint_account, &escrow_info.requested_mint, EscrowError::RequestedMintMismatch)?;
+            Some(requested_mint_account)
+        } else {
+            None
+        };
+
+        // 15. Grab the Associated Token Account program when Bob wants to receive the offered
+        // tokens in his associated token accounts. That way he doesn't have to create them
+        // in a separate instruction before taking the trade.
+        let associated_token_accounts = if use_associated_token_account {
+            let associated_token_program = next_account_info(account_info_iter)?;
+            assert_program(associated_token_program, &token::spl_associated_token_account::id())?;
+            Some(AssociatedTokenAccounts {
+                payer: taker,
+                wallet: taker,
+                system_program,
+                associated_token_program,
+            })
+        } else {
+            None
+        };
 
         // Time to transfer Y tokens from Bob's account to Alice's Y token account
         // To do this, we're actually creating an Transfer Instruction.
-        // NOTE To perform the actual transfer we use spl_token::instruction::transfer built-in
-        // method, which is a CPI. We then will use invoke() to call this new instruction
+        // NOTE To perform the actual transfer we use token::transfer_checked (spl_token's built-in
+        // transfer_checked instruction, sent to the right token program), which is a CPI. We then will use invoke() to call this new instruction
         // and pass in this instruction along with the accounts involved.
         // NOTE This is using Signature Extension to make the token transfer to Alice's Y
         // token account on Bob's behalf.
-        let transfer_to_initializer_ix = spl_token::instruction::transfer(
-            token_program.key,
-            takers_sending_token_account.key, // source (Bob's Y token account)
-            initializers_token_to_receive_account.key, // destination (Alice's Y token account)
-            taker.key, // authority_pubkey (Bob's main account since he's authorizing the trade)
-            &[&taker.key], // signers array
-            escrow_info.expected_amount, // This is the amount passed to InitEscrow, i.e., Alice's X token amount
-            // NOTE Or, in other words, the agreed upon amount Bob expects to receive in X tokens for
-            // his Y tokens he's going to transfer to Alice.
-        )?;
-        msg!("Calling the token program to transfer tokens to the escrow's initializer...");
-        invoke(
-            &transfer_to_initializer_ix, // CPI instruction
-            &[
-                takers_sending_token_account.clone(), // Bob's Y token account
-                initializers_token_to_receive_account.clone(), // Alice's Y token account
-                taker.clone(), // Bob's main account
-                token_program.clone(), // Token Program AccountInfo.
-                // NOTE The token program key (id) is the program id of this
-                // transfer_to_initializer_ix
-            ],
-        )?;
+        if escrow_info.requested_kind == AssetKind::Native {
+            if fee_amount > 0 {
+                let transfer_fee_ix = system_instruction::transfer(
+                    taker.key, // source (Bob's main account)
+                    fee_recipient_account.key, // destination (the treasury)
+                    fee_amount,
+                );
+                msg!("Calling the system program to transfer the protocol fee to the treasury...");
+                invoke(
+                    &transfer_fee_ix,
+                    &[
+                        taker.clone(),
+                        fee_recipient_account.clone(),
+                        system_program.clone(),
+                    ],
+                )?;
+            }
+            let transfer_to_initializer_ix = system_instruction::transfer(
+                taker.key, // source (Bob's main account)
+                initializers_token_to_receive_account.key, // destination (Alice's main account)
+                initializer_amount, // Bob's proportional share of the lamports Alice asked for, minus the fee
+            );
+            msg!("Calling the system program to transfer lamports to the escrow's initializer...");
+            invoke(
+                &transfer_to_initializer_ix,
+                &[
+                    taker.clone(),
+                    initializers_token_to_receive_account.clone(),
+                    system_program.clone(),
+                ],
+            )?;
+        } else if let Some(requested_mint_account) = requested_mint_account {
+            // NOTE A Token-2022 mint may charge a fee on every transfer, which the token program
+            // takes out of what the recipient gets. So Bob pays the transfer fee on top, making
+            // sure Alice (and the treasury) receive exactly what they're owed.
+            let transfer_fee = token::transfer_fee(&requested_mint_account.data.borrow(), clock.epoch)?;
+            let with_transfer_fee = |amount: u64| {
+                transfer_fee
+                    .calculate_pre_fee_amount(amount)
+                    .ok_or(EscrowError::AmountOverflow)
+            };
+            if fee_amount > 0 {
+                let transfer_fee_ix = token::transfer_checked(
+                    requested_token_program.key,
+                    takers_sending_token_account.key, // source (Bob's Y token account)
+                    requested_mint_account.key, // the Y token's mint
+                    fee_recipient_account.key, // destination (the treasury's Y token account)
+                    taker.key, // authority_pubkey
+                    &[taker.key], // signers array
+                    with_transfer_fee(fee_amount)?,
+                    escrow_info.requested_decimals,
+                )?;
+                msg!("Calling the token program to transfer the protocol fee to the treasury...");
+                invoke(
+                    &transfer_fee_ix,
+                    &[
+                        takers_sending_token_account.clone(),
+                        requested_mint_account.clone(),
+                        fee_recipient_account.clone(),
+                        taker.clone(),
+                        requested_token_program.clone(),
+                    ],
+                )?;
+            }
+            // Remember Alice's balance, so we can check what actually arrived
+            let initializer_balance_before = token::unpack_account(&initializers_token_to_receive_account.data.borrow())?.amount;
+            let transfer_to_initializer_ix = token::transfer_checked(
+                requested_token_program.key,
+                takers_sending_token_account.key, // source (Bob's Y token account)
+                requested_mint_account.key, // the Y token's mint
+                initializers_token_to_receive_account.key, // destination (Alice's Y token account)
+                taker.key, // authority_pubkey (Bob's main account since he's authorizing the trade)
+                &[taker.key], // signers array
+                with_transfer_fee(initializer_amount)?, // Bob's proportional share of the amount passed to InitEscrow, minus the fee
+                // NOTE If Bob takes the whole offer, fee and initializer_amount add up to exactly the
+                // agreed upon expected_amount of Y tokens Alice asked for in exchange for all of her X tokens.
+                escrow_info.requested_decimals, // the token program double-checks these against the mint
+            )?;
+            msg!("Calling the token program to transfer tokens to the escrow's initializer...");
+            invoke(
+                &transfer_to_initializer_ix, // CPI instruction
+                &[
+                    takers_sending_token_account.clone(), // Bob's Y token account
+                    requested_mint_account.clone(), // The Y token's mint
+                    initializers_token_to_receive_account.clone(), // Alice's Y token account
+                    taker.clone(), // Bob's main account
+                    requested_token_program.clone(), // Token Program AccountInfo.
+                    // NOTE The token program key (id) is the program id of this
+                    // transfer_to_initializer_ix
+                ],
+            )?;
+            // IMPORTANT: Don't just trust our fee math. If Alice ended up with less than she
+            // asked for (e.g., the mint's fee changed), the whole trade fails.
+            let initializer_balance_after = token::unpack_account(&initializers_token_to_receive_account.data.borrow())?.amount;
+            if initializer_balance_after.saturating_sub(initializer_balance_before) < initializer_amount {
+                return Err(EscrowError::ReceivedAmountTooLow.into());
+            }
+        }
 
 
-        // 8. Time to transfer X tokens from temp X token account to Bob's main X token account
-        // NOTE The PDA has authority on the temp X token account
-        let pda_account = next_account_info(account_info_iter)?;
+        // Time to transfer X tokens from temp X token account to Bob's main X token account
+        // NOTE On the final fill we send Bob everything that's left in the temp account,
+        // since the token program won't close an account that still holds tokens (e.g.,
+        // if someone sent extra X tokens to it).
+        // NOTE An NFT escrow always hands over exactly the one NFT it holds.
+        let amount_to_taker = if escrow_info.offered_kind == AssetKind::Nft {
+            1
+        } else if is_fully_filled {
+            pdas_temp_token_account_amount
+        } else {
+            fill_amount
+        };
+
+        if escrow_info.offered_kind == AssetKind::Native {
+            // Our program owns the vault holding Alice's lamports, so we can simply move them
+            // to Bob without any CPI. Once the offer is fully filled, whatever is left (the
+            // vault's rent) goes back to Alice and the vault gets purged by the runtime.
+            msg!("Transferring lamports to the taker from the escrow's vault...");
+            Self::transfer_lamports(pdas_temp_token_account, takers_receiving_token_account, amount_to_taker)?;
+            if !is_fully_filled {
+                Escrow::pack(escrow_info, &mut escrow_account.data.borrow_mut())?;
+                return Ok(());
+            }
+            Self::transfer_lamports(pdas_temp_token_account, initializers_main_account, pdas_temp_token_account.lamports())?;
+            Self::close_escrow_account(escrow_account, initializers_main_account)?;
+            return Ok(());
+        }
+
+        // Only a native offer comes without a mint, and we're done with those above
+        let offered_mint_account = offered_mint_account.ok_or(EscrowError::OfferedMintMismatch)?;
+
+        // Make sure Bob's associated token account for the X token exists before we send to it
+        if let Some(associated_token_accounts) = &associated_token_accounts {
+            associated_token_accounts.create_idempotent(takers_receiving_token_account, offered_mint_account, token_program)?;
+        }
 
         // Create another Transfer Instruction
-        let transfer_to_taker_ix = spl_token::instruction::transfer(
+        // NOTE Should the X token's mint charge a transfer fee, it comes out of what Bob
+        // receives, like with any other Token-2022 transfer.
+        let transfer_to_taker_ix = token::transfer_checked(
             token_program.key,
             pdas_temp_token_account.key, // source. Q: Why not from pda_account?
+            offered_mint_account.key, // the X token's mint
             takers_receiving_token_account.key, // destination (Bob's X token account).
             &pda, // authority_pubkey (retrieved from find_program_address() above)
             &[&pda],  // signers array. Again, the PDA is the signer
-            pdas_temp_token_account_info.amount,// amount to transfer (inside PDA AccountInfo)
+            amount_to_taker, // amount to transfer (Bob's fill)
+            escrow_info.offered_decimals, // the decimals Alice's X token had in InitEscrow
         )?;
         // Now time to invoke this instruction
         // NOTE This uses invoke_signed to allow the PDA to sign something. Recall that
@@ -379,15 +948,40 @@ impl Processor {
             &transfer_to_taker_ix, // CPI instruction
             &[
                 pdas_temp_token_account.clone(),
+                offered_mint_account.clone(),
                 takers_receiving_token_account.clone(),
                 pda_account.clone(),
                 token_program.clone(),
             ],
-            &[&[&b"escrow"[..], &[bump_seed]]], // seeds used to create the PDA
+            &[pda_signer_seeds], // seeds used to create the PDA
         )?;
 
+        // If the offer still has X tokens left, save the new remaining_amount and
+        // keep both the temp token account and escrow account open for the next taker.
+        if !is_fully_filled {
+            Escrow::pack(escrow_info, &mut escrow_account.data.borrow_mut())?;
+            return Ok(());
+        }
 
-        // 9. Need to tidy up and close the temp PDA account using invoke_signed fn
+        // 16. Settle the extra tokens of a basket trade along with the first ones.
+        // NOTE Baskets are tokens only, so both mints (13 and 14) were passed in and the
+        // legs are simply the rest of the accounts.
+        if escrow_info.is_basket() {
+            Self::settle_basket(
+                &escrow_info,
+                account_info_iter.as_slice(),
+                taker,
+                initializers_main_account,
+                &config,
+                token_program,
+                requested_token_program,
+                pda_account,
+                pda_signer_seeds,
+                associated_token_accounts.as_ref(),
+            )?;
+        }
+
+        // 17. Need to tidy up and close the temp PDA account using invoke_signed fn
         // NOTE Accounts are required to have a min balance to be rent exempt. 
         // So, when we no longer need an account (ie close the account), we can recover
         // the balance by transferring it to a different account.
@@ -397,7 +991,25 @@ impl Processor {
         // decrease the balance. And because this action requires permission of the
         // (user space) owner of the token account (i.e., PDA in this case), we use
         // invoked_signed() fn again.
-        let close_pdas_temp_acc_ix = spl_token::instruction::close_account(
+        // NOTE Token-2022 withholds the transfer fee of Alice's deposit IN the vault, and won't
+        // close an account holding withheld fees. Anybody may harvest them into the mint though.
+        if token::withheld_amount(&pdas_temp_token_account.data.borrow())? > 0 {
+            let harvest_ix = token::harvest_withheld_tokens_to_mint(
+                token_program.key,
+                offered_mint_account.key,
+                &[pdas_temp_token_account.key],
+            )?;
+            msg!("Calling the token program to harvest the vault's withheld transfer fees...");
+            invoke(
+                &harvest_ix,
+                &[
+                    offered_mint_account.clone(),
+                    pdas_temp_token_account.clone(),
+                    token_program.clone(),
+                ],
+            )?;
+        }
+        let close_pdas_temp_acc_ix = token::close_account(
             token_program.key,
             pdas_temp_token_account.key,
             initializers_main_account.key,
@@ -413,18 +1025,1107 @@ impl Processor {
                 pda_account.clone(),
                 token_program.clone(),
             ],
-            &[&[&b"escrow"[..], &[bump_seed]]],
+            &[pda_signer_seeds],
+        )?;
+
+        // 18. Time to close the Escrow (state) account to conclude this program
+        Self::close_escrow_account(escrow_account, initializers_main_account)
+    }
+
+
+    fn process_cancel(
+        accounts: &[AccountInfo],
+        use_associated_token_account: bool,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        // IMPORTANT: This is Alice's Transaction. Nobody has taken the trade yet,
+        // so Alice wants her X tokens back from the PDA's temp token account and
+        // the rent she paid for the temp and escrow accounts.
+        //
+        //
+        // 0. `[signer, writable]` The account of the person who initialized the escrow (Alice)
+        // 1. `[writable]` The escrow's vault (the PDA's temp token account) to refund tokens from and eventually close
+        // 2. `[writable]` The initializer's token account that will get the deposited tokens back
+        //    (her associated token account, created if needed, when use_associated_token_account is set)
+        // 3. `[writable]` The escrow account holding the escrow info
+        // 4. `[]` The token program of the offered token
+        // 5. `[]` The PDA account
+        // 6. `[]` The mint of the offered token, `[writable]` with Token-2022 (not passed in when native SOL was offered)
+        // 7. `[]` The system program (only passed in when use_associated_token_account is set)
+        // 8. `[]` The associated token account program (only passed in when use_associated_token_account is set)
+        // 9. (or 7.) onwards: (vault, refund token account, mint) for every extra offered token of a basket trade
+        let account_info_iter = &mut accounts.iter();
+
+        // 0. Grab the initializer (Alice) and make sure she actually signed
+        let initializer = next_account_info(account_info_iter)?;
+        assert_signer(initializer)?;
+        assert_writable(initializer)?;
+
+        // 1. Grab Alice's temp X token account that's currently owned by the PDA
+        // (or the vault holding her lamports when she offered native SOL)
+        let pdas_temp_token_account = next_account_info(account_info_iter)?;
+        assert_writable(pdas_temp_token_account)?;
+
+        // 2. Grab the token account the X tokens should be returned to.
+        // NOTE We don't need to save this account in the escrow state since Alice
+        // is signing and can send her own tokens wherever she likes.
+        // NOTE Native SOL is always returned to Alice's main account, so this account
+        // is ignored when Alice offered native SOL.
+        let initializers_refund_token_account = next_account_info(account_info_iter)?;
+
+        // 3. Grab the Escrow State Account and make sure it really belongs to Alice.
+        // Otherwise anyone could cancel somebody else's trade!
+        let escrow_account = next_account_info(account_info_iter)?;
+        let escrow_info = load_escrow(escrow_account, program_id)?;
+        assert_address(initializer, &escrow_info.initializer_pubkey, EscrowError::InitializerMismatch)?;
+        assert_address(pdas_temp_token_account, &escrow_info.temp_token_account_pubkey, EscrowError::InvalidVaultAddress)?;
+        // NOTE Alice's associated token account may not exist yet, so it's checked when we
+        // create it instead (see refund_offered_side).
+        if escrow_info.offered_kind != AssetKind::Native {
+            assert_writable(initializers_refund_token_account)?;
+            if !use_associated_token_account {
+                let initializers_refund_token_account_info =
+                    unpack_token_account(initializers_refund_token_account, &escrow_info.offered_token_program.id())?;
+                if initializers_refund_token_account_info.mint != escrow_info.offered_mint {
+                    return Err(EscrowError::OfferedMintMismatch.into());
+                }
+            }
+        }
+
+        // 4. Grab the Token Program account the offered tokens live in
+        let token_program = next_account_info(account_info_iter)?;
+        assert_program(token_program, &escrow_info.offered_token_program.id())?;
+
+        // 5. Grab the PDA account, which has authority over the temp X token account
+        let pda_account = next_account_info(account_info_iter)?;
+        let pda = Self::escrow_pda(escrow_account.key, escrow_info.bump_seed, program_id)?;
+        assert_address(pda_account, &pda, EscrowError::InvalidPdaAccount)?;
+        let pda_signer_seeds: &[&[u8]] = &[&b"escrow"[..], escrow_account.key.as_ref(), &[escrow_info.bump_seed]];
+
+        // 6. Grab the mint of the offered X token (unless Alice offered native SOL)
+        let offered_mint_account = Self::next_offered_mint_account(&escrow_info, account_info_iter)?;
+
+        // 7. and 8. Grab the system and Associated Token Account programs when Alice wants her
+        // tokens back in her associated token accounts. Alice pays for any we have to create.
+        let associated_token_accounts = if use_associated_token_account {
+            Some(Self::next_associated_token_accounts(initializer, initializer, account_info_iter)?)
+        } else {
+            None
+        };
+
+        // Send the X tokens (or lamports) and the rent of both accounts back to Alice
+        Self::refund_offered_side(
+            &escrow_info,
+            pdas_temp_token_account,
+            offered_mint_account,
+            initializers_refund_token_account,
+            initializer,
+            token_program,
+            pda_account,
+            pda_signer_seeds,
+            associated_token_accounts.as_ref(),
+        )?;
+        // 9. (or 7.) Alice signed, so the extra tokens of a basket trade can go wherever she likes too
+        Self::refund_basket(
+            &escrow_info,
+            account_info_iter.as_slice(),
+            initializer,
+            token_program,
+            pda_account,
+            pda_signer_seeds,
+            false,
+            associated_token_accounts.as_ref(),
+        )?;
+        Self::close_escrow_account(escrow_account, initializer)?;
+
+        Ok(())
+    }
+
+
+    fn process_refund_expired(
+        accounts: &[AccountInfo],
+        use_associated_token_account: bool,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        // NOTE Anybody can crank this instruction once an escrow has expired. Since the
+        // initializer isn't signing, we have to make sure the tokens and rent can ONLY
+        // go back to accounts that belong to her.
+        //
+        //
+        // 0. `[writable]` The escrow's vault (the PDA's temp token account) to refund tokens from and eventually close
+        // 1. `[writable]` A token account owned by the initializer that will get the deposited tokens back
+        //    (her associated token account, created if needed, when use_associated_token_account is set)
+        // 2. `[writable]` The initializer's main account to send their rent fees to
+        // 3. `[writable]` The escrow account holding the escrow info
+        // 4. `[]` The token program of the offered token
+        // 5. `[]` The PDA account
+        // 6. `[]` The mint of the offered token, `[writable]` with Token-2022 (not passed in when native SOL was offered)
+        // 7. `[signer, writable]` The account paying for new associated token accounts (only passed in when use_associated_token_account is set)
+        // 8. `[]` The system program (only passed in when use_associated_token_account is set)
+        // 9. `[]` The associated token account program (only passed in when use_associated_token_account is set)
+        // 10. (or 7.) onwards: (vault, refund token account, mint) for every extra offered token of a basket trade
+        let account_info_iter = &mut accounts.iter();
+
+        // 0. Grab Alice's temp X token account that's currently owned by the PDA
+        // (or the vault holding her lamports when she offered native SOL)
+        let pdas_temp_token_account = next_account_info(account_info_iter)?;
+        assert_writable(pdas_temp_token_account)?;
+
+        // 1. Grab the token account the X tokens should be returned to. It must be one of
+        // Alice's token accounts for the offered mint, otherwise the cranker could steal them.
+        // NOTE Native SOL is always returned to Alice's main account, so this account
+        // is ignored when Alice offered native SOL.
+        let initializers_refund_token_account = next_account_info(account_info_iter)?;
+
+        // 2. Grab Alice's main account so she gets the rent back
+        let initializers_main_account = next_account_info(account_info_iter)?;
+        assert_writable(initializers_main_account)?;
+
+        // 3. Grab the Escrow State Account and check everything against it
+        let escrow_account = next_account_info(account_info_iter)?;
+        let escrow_info = load_escrow(escrow_account, program_id)?;
+        assert_address(initializers_main_account, &escrow_info.initializer_pubkey, EscrowError::InitializerMismatch)?;
+        // NOTE Alice's associated token account may not exist yet. It's hers by definition
+        // though, and we check it's the canonical one when we create it (see refund_offered_side).
+        if escrow_info.offered_kind != AssetKind::Native {
+            assert_writable(initializers_refund_token_account)?;
+            if !use_associated_token_account {
+                let initializers_refund_token_account_info =
+                    unpack_token_account(initializers_refund_token_account, &escrow_info.offered_token_program.id())?;
+                if escrow_info.initializer_pubkey != initializers_refund_token_account_info.owner {
+                    return Err(EscrowError::InvalidRefundAccount.into());
+                }
+                if initializers_refund_token_account_info.mint != escrow_info.offered_mint {
+                    return Err(EscrowError::OfferedMintMismatch.into());
+                }
+            }
+        }
+        assert_address(pdas_temp_token_account, &escrow_info.temp_token_account_pubkey, EscrowError::InvalidVaultAddress)?;
+
+        // Only expired escrows can be refunded by a third party. Escrows without
+        // a deadline can only be cancelled by the initializer herself.
+        let clock = Clock::get()?;
+        if !escrow_info.is_expired(clock.unix_timestamp) {
+            return Err(EscrowError::EscrowNotExpired.into());
+        }
+
+        // 4. Grab the Token Program account the offered tokens live in
+        let token_program = next_account_info(account_info_iter)?;
+        assert_program(token_program, &escrow_info.offered_token_program.id())?;
+
+        // 5. Grab the PDA account, which has authority over the temp X token account
+        let pda_account = next_account_info(account_info_iter)?;
+        let pda = Self::escrow_pda(escrow_account.key, escrow_info.bump_seed, program_id)?;
+        assert_address(pda_account, &pda, EscrowError::InvalidPdaAccount)?;
+        let pda_signer_seeds: &[&[u8]] = &[&b"escrow"[..], escrow_account.key.as_ref(), &[escrow_info.bump_seed]];
+
+        // 6. Grab the mint of the offered X token (unless Alice offered native SOL)
+        let offered_mint_account = Self::next_offered_mint_account(&escrow_info, account_info_iter)?;
+
+        // 7. to 9. Grab whoever pays for Alice's new associated token accounts (most likely
+        // the crank) along with the system and Associated Token Account programs
+        let associated_token_accounts = if use_associated_token_account {
+            let payer = next_account_info(account_info_iter)?;
+            assert_signer(payer)?;
+            assert_writable(payer)?;
+            Some(Self::next_associated_token_accounts(payer, initializers_main_account, account_info_iter)?)
+        } else {
+            None
+        };
+
+        Self::refund_offered_side(
+            &escrow_info,
+            pdas_temp_token_account,
+            offered_mint_account,
+            initializers_refund_token_account,
+            initializers_main_account,
+            token_program,
+            pda_account,
+            pda_signer_seeds,
+            associated_token_accounts.as_ref(),
+        )?;
+        // 10. (or 7.) The extra tokens of a basket trade must go back to Alice's token accounts as well
+        Self::refund_basket(
+            &escrow_info,
+            account_info_iter.as_slice(),
+            initializers_main_account,
+            token_program,
+            pda_account,
+            pda_signer_seeds,
+            true,
+            associated_token_accounts.as_ref(),
+        )?;
+        Self::close_escrow_account(escrow_account, initializers_main_account)?;
+
+        Ok(())
+    }
+
+    fn process_init_config(
+        accounts: &[AccountInfo],
+        fee_bps: u16,
+        treasury: Pubkey,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        // 0. `[signer, writable]` The program's upgrade authority, paying for the config account and becoming its admin
+        // 1. `[writable]` The config account PDA, created by this instruction
+        // 2. `[]` The system program
+        // 3. `[]` The program's ProgramData account
+        let account_info_iter = &mut accounts.iter();
+
+        let payer = next_account_info(account_info_iter)?;
+        assert_signer(payer)?;
+        assert_writable(payer)?;
+
+        // The config account lives at a PDA with a static seed, so there can only ever be
+        // ONE of them. NOTE Since create_account fails for an existing account, whoever
+        // creates the config first becomes its admin. That's why only the account that
+        // deployed (i.e., can upgrade) the program may do so, see step 3.
+        let config_account = next_account_info(account_info_iter)?;
+        assert_writable(config_account)?;
+        let (config_address, config_bump_seed) = Pubkey::find_program_address(&[b"config"], program_id);
+        assert_address(config_account, &config_address, EscrowError::InvalidConfigAddress)?;
+
+        let system_program = next_account_info(account_info_iter)?;
+        assert_program(system_program, &solana_program::system_program::id())?;
+
+        // 3. Grab the program's ProgramData account, which records its upgrade authority.
+        // IMPORTANT: Otherwise anybody could front-run us right after the deploy, become
+        // the admin and send the protocol fees to their own treasury.
+        let program_data_account = next_account_info(account_info_iter)?;
+        assert_upgrade_authority(program_data_account, payer, program_id)?;
+
+        if fee_bps > Config::MAX_FEE_BPS {
+            return Err(EscrowError::InvalidFee.into());
+        }
+
+        let rent = Rent::get()?;
+        msg!("Calling the system program to create the config account...");
+        Self::create_pda_account(
+            payer,
+            config_account,
+            rent.minimum_balance(Config::LEN),
+            Config::LEN,
+            program_id,
+            system_program,
+            &[&b"config"[..], &[config_bump_seed]],
+        )?;
+
+        let config = Config {
+            is_initialized: true,
+            admin: *payer.key,
+            fee_bps,
+            treasury,
+            paused: false,
+            bump_seed: config_bump_seed,
+        };
+        Config::pack(config, &mut config_account.data.borrow_mut())?;
+
+        Ok(())
+    }
+
+    fn process_update_config(
+        accounts: &[AccountInfo],
+        fee_bps: u16,
+        treasury: Pubkey,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        // 0. `[signer]` The config's admin
+        // 1. `[writable]` The config account
+        let account_info_iter = &mut accounts.iter();
+        let admin = next_account_info(account_info_iter)?;
+        let config_account = next_account_info(account_info_iter)?;
+        let mut config = Self::load_config_as_admin(admin, config_account, program_id)?;
+
+        if fee_bps > Config::MAX_FEE_BPS {
+            return Err(EscrowError::InvalidFee.into());
+        }
+        config.fee_bps = fee_bps;
+        config.treasury = treasury;
+        Config::pack(config, &mut config_account.data.borrow_mut())?;
+
+        Ok(())
+    }
+
+    fn process_transfer_admin(
+        accounts: &[AccountInfo],
+        new_admin: Pubkey,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        // 0. `[signer]` The config's current admin
+        // 1. `[writable]` The config account
+        let account_info_iter = &mut accounts.iter();
+        let admin = next_account_info(account_info_iter)?;
+        let config_account = next_account_info(account_info_iter)?;
+        let mut config = Self::load_config_as_admin(admin, config_account, program_id)?;
+
+        // NOTE The new admin doesn't need to sign, so double-check the address before
+        // sending this. Handing the config to an account nobody controls locks it forever!
+        config.admin = new_admin;
+        Config::pack(config, &mut config_account.data.borrow_mut())?;
+
+        Ok(())
+    }
+
+    fn process_set_paused(
+        accounts: &[AccountInfo],
+        paused: bool,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        // 0. `[signer]` The config's admin
+        // 1. `[writable]` The config account
+        let account_info_iter = &mut accounts.iter();
+        let admin = next_account_info(account_info_iter)?;
+        let config_account = next_account_info(account_info_iter)?;
+        let mut config = Self::load_config_as_admin(admin, config_account, program_id)?;
+
+        config.paused = paused;
+        Config::pack(config, &mut config_account.data.borrow_mut())?;
+
+        Ok(())
+    }
+
+    fn process_migrate_escrow(
+        accounts: &[AccountInfo],
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        // NOTE Escrows opened before we versioned the account data (legacy escrows) have
+        // to be grown to Escrow::LEN and rewritten in the latest layout. The tokens never
+        // leave the vault, so anybody may do this.
+        //
+        //
+        // 0. `[signer, writable]` The account paying for the extra rent
+        // 1. `[writable]` The legacy escrow account
+        // 2. `[]` The system program
+        // 3.-8. See migrate_legacy_escrow
+        let account_info_iter = &mut accounts.iter();
+
+        let payer = next_account_info(account_info_iter)?;
+        assert_signer(payer)?;
+        assert_writable(payer)?;
+
+        // 1. Grab the escrow account. Only a legacy escrow is LegacyEscrow::LEN bytes long.
+        let escrow_account = next_account_info(account_info_iter)?;
+        assert_owned_by(escrow_account, program_id)?;
+        assert_writable(escrow_account)?;
+        if escrow_account.data_len() != LegacyEscrow::LEN {
+            return Err(EscrowError::EscrowAlreadyMigrated.into());
+        }
+
+        let system_program = next_account_info(account_info_iter)?;
+        assert_program(system_program, &solana_program::system_program::id())?;
+
+        let escrow_info = Self::migrate_legacy_escrow(escrow_account, account_info_iter, program_id)?;
+
+        // The bigger account needs more rent to stay rent exempt, so the payer tops it up
+        let rent = Rent::get()?;
+        let top_up_lamports = rent
+            .minimum_balance(Escrow::LEN)
+            .saturating_sub(escrow_account.lamports());
+        if top_up_lamports > 0 {
+            invoke(
+                &system_instruction::transfer(payer.key, escrow_account.key, top_up_lamports),
+                &[payer.clone(), escrow_account.clone(), system_program.clone()],
+            )?;
+        }
+
+        // Rewrite the account in the latest layout
+        Self::realloc_account(escrow_account, Escrow::LEN)?;
+        Escrow::pack(escrow_info, &mut escrow_account.data.borrow_mut())?;
+
+        Ok(())
+    }
+
+    // Decodes an escrow opened before we versioned the account data.
+    // NOTE Legacy escrows are LegacyEscrow::LEN bytes long and their vault is owned by
+    // the single PDA `[b"escrow"]`, so we also hand the vault over to the escrow's own PDA.
+    //
+    // 3. `[writable]` The escrow's vault (the legacy temp token account)
+    // 4. `[]` The initializer's token account that will receive tokens
+    // 5. `[]` The legacy PDA `[b"escrow"]` that currently owns the vault
+    // 6. `[]` The token program
+    // 7. `[]` The mint of the offered token
+    // 8. `[]` The mint of the requested token
+    fn migrate_legacy_escrow<'a, 'b>(
+        escrow_account: &AccountInfo<'a>,
+        account_info_iter: &mut std::slice::Iter<'b, AccountInfo<'a>>,
+        program_id: &Pubkey,
+    ) -> Result<Escrow, ProgramError> {
+        // Decode the legacy escrow account with the OLD layout
+        let legacy_info = LegacyEscrow::unpack(&escrow_account.data.borrow())?;
+        if !legacy_info.is_initialized {
+            return Err(ProgramError::UninitializedAccount);
+        }
+
+        // 3. Grab the vault. Its balance and mint become the offer of the migrated escrow.
+        let pdas_temp_token_account = next_account_info(account_info_iter)?;
+        assert_writable(pdas_temp_token_account)?;
+        assert_address(pdas_temp_token_account, &legacy_info.temp_token_account_pubkey, EscrowError::InvalidVaultAddress)?;
+        let pdas_temp_token_account_info = unpack_token_account(pdas_temp_token_account, &spl_token::id())?;
+
+        // 4. Grab Alice's Y token account to find out which mint she's requesting
+        let initializers_token_to_receive_account = next_account_info(account_info_iter)?;
+        assert_address(
+            initializers_token_to_receive_account,
+            &legacy_info.initializer_token_to_receive_account_pubkey,
+            EscrowError::ReceiveAccountMismatch,
+        )?;
+        let initializers_token_to_receive_account_info =
+            unpack_token_account(initializers_token_to_receive_account, &spl_token::id())?;
+
+        // 5. Grab the legacy PDA. NOTE Back then every vault was owned by the same PDA.
+        let legacy_pda_account = next_account_info(account_info_iter)?;
+        let (legacy_pda, legacy_bump_seed) = Pubkey::find_program_address(&[b"escrow"], program_id);
+        assert_address(legacy_pda_account, &legacy_pda, EscrowError::InvalidPdaAccount)?;
+        if pdas_temp_token_account_info.owner != legacy_pda {
+            return Err(EscrowError::InvalidVaultAddress.into());
+        }
+
+        let token_program = next_account_info(account_info_iter)?;
+        assert_program(token_program, &spl_token::id())?;
+
+        // 7. and 8. Legacy escrows didn't save the decimals of their tokens, so read them
+        // from the mints
+        let offered_mint_account = next_account_info(account_info_iter)?;
+        assert_address(offered_mint_account, &pdas_temp_token_account_info.mint, EscrowError::OfferedMintMismatch)?;
+        let offered_decimals = unpack_mint(offered_mint_account, &spl_token::id())?.decimals;
+        let requested_mint_account = next_account_info(account_info_iter)?;
+        assert_address(
+            requested_mint_account,
+            &initializers_token_to_receive_account_info.mint,
+            EscrowError::RequestedMintMismatch,
+        )?;
+        let requested_decimals = unpack_mint(requested_mint_account, &spl_token::id())?.decimals;
+
+        // Hand the vault over to the escrow's own PDA, just like InitEscrow does today
+        let (pda, bump_seed) = Pubkey::find_program_address(&[b"escrow", escrow_account.key.as_ref()], program_id);
+        let set_vault_authority_ix = token::set_authority(
+            token_program.key,
+            pdas_temp_token_account.key,
+            Some(&pda),
+            spl_token::instruction::AuthorityType::AccountOwner,
+            &legacy_pda,
+            &[&legacy_pda],
+        )?;
+        msg!("Calling the token program to hand the vault over to the escrow's PDA...");
+        invoke_signed(
+            &set_vault_authority_ix,
+            &[
+                pdas_temp_token_account.clone(),
+                legacy_pda_account.clone(),
+                token_program.clone(),
+            ],
+            &[&[&b"escrow"[..], &[legacy_bump_seed]]],
+        )?;
+
+        // A legacy escrow was always an all-or-nothing token for token trade without a deadline.
+        Ok(Escrow {
+            is_initialized: true,
+            initializer_pubkey: legacy_info.initializer_pubkey,
+            temp_token_account_pubkey: legacy_info.temp_token_account_pubkey,
+            initializer_token_to_receive_account_pubkey: legacy_info.initializer_token_to_receive_account_pubkey,
+            expected_amount: legacy_info.expected_amount,
+            bump_seed,
+            expires_at: None,
+            offered_amount: pdas_temp_token_account_info.amount,
+            remaining_amount: pdas_temp_token_account_info.amount,
+            offered_mint: pdas_temp_token_account_info.mint,
+            requested_mint: initializers_token_to_receive_account_info.mint,
+            offered_kind: AssetKind::Token,
+            requested_kind: AssetKind::Token,
+            // NOTE Legacy escrow accounts weren't PDAs, so there's no nonce to save
+            nonce: 0,
+            allowed_taker: None,
+            taker_merkle_root: None,
+            basket_offered: Vec::new(),
+            basket_requested: Vec::new(),
+            offered_collection: None,
+            // Token-2022 didn't exist back then
+            offered_token_program: TokenProgram::SplToken,
+            requested_token_program: TokenProgram::SplToken,
+            offered_decimals,
+            requested_decimals,
+            basket_offered_decimals: Vec::new(),
+            basket_requested_decimals: Vec::new(),
+        })
+    }
+
+    // Grows (or shrinks) a program-owned account's data in place.
+    // NOTE The runtime lets a program change the length of its accounts' data by up to
+    // MAX_PERMITTED_DATA_INCREASE bytes per instruction. The entrypoint leaves that much
+    // room after every account's data and reads the new length back from the u64 that
+    // sits right in front of the data. Newer solana-program versions wrap this up as
+    // AccountInfo::realloc(), which doesn't exist in the version we're on.
+    fn realloc_account(account: &AccountInfo, new_len: usize) -> ProgramResult {
+        if new_len.saturating_sub(account.data_len()) > MAX_PERMITTED_DATA_INCREASE {
+            return Err(ProgramError::AccountDataTooSmall);
+        }
+        let old_len = account.data_len();
+        let mut data = account.try_borrow_mut_data()?;
+        let data_ptr = data.as_mut_ptr();
+        // SAFETY: The entrypoint serialized the account's length right in front of its
+        // data and reserved MAX_PERMITTED_DATA_INCREASE bytes after it (see above).
+        unsafe {
+            *(data_ptr.offset(-8) as *mut u64) = new_len as u64;
+            *data = std::slice::from_raw_parts_mut(data_ptr, new_len);
+        }
+        // Zero the new bytes, since they might hold leftovers of the reserved space
+        if new_len > old_len {
+            for byte in data[old_len..].iter_mut() {
+                *byte = 0;
+            }
+        }
+        Ok(())
+    }
+
+    // Makes sure the program isn't paused before opening or taking an escrow.
+    // NOTE The config account sits at a fixed position in InitEscrow's and Exchange's
+    // account lists, so we can check it before running the instruction itself.
+    fn assert_not_paused(
+        config_account: Option<&AccountInfo>,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        let config_account = config_account.ok_or(ProgramError::NotEnoughAccountKeys)?;
+        let config = load_config(config_account, program_id)?;
+        if config.paused {
+            return Err(EscrowError::ProgramPaused.into());
+        }
+        Ok(())
+    }
+
+    // Loads the config account for an admin-only instruction, making sure the
+    // config's admin really signed the transaction.
+    fn load_config_as_admin(
+        admin: &AccountInfo,
+        config_account: &AccountInfo,
+        program_id: &Pubkey,
+    ) -> Result<Config, ProgramError> {
+        assert_signer(admin)?;
+        assert_writable(config_account)?;
+        let config = load_config(config_account, program_id)?;
+        assert_address(admin, &config.admin, EscrowError::UnauthorizedAdmin)?;
+        Ok(config)
+    }
+
+    // Creates a vault token account for `mint` at a PDA of this program, owned by the
+    // escrow's PDA, and moves `amount` tokens from the initializer's token account into it.
+    // NOTE Should the mint charge a Token-2022 transfer fee, the initializer pays it on top,
+    // so the vault ends up holding exactly `amount` tokens.
+    #[allow(clippy::too_many_arguments)]
+    fn open_token_vault<'a>(
+        initializer: &AccountInfo<'a>,
+        initializers_sending_token_account: &AccountInfo<'a>,
+        vault: &AccountInfo<'a>,
+        mint: &AccountInfo<'a>,
+        pda: &Pubkey,
+        amount: u64,
+        decimals: u8,
+        rent: &Rent,
+        rent_sysvar: &AccountInfo<'a>,
+        token_program: &AccountInfo<'a>,
+        system_program: &AccountInfo<'a>,
+        vault_signer_seeds: &[&[u8]],
+    ) -> ProgramResult {
+        // Allocate the vault and hand it over to the token program. The vault "signs" the
+        // system program CPI with its seeds, just like the PDA does in Exchange.
+        msg!("Calling the system program to create the escrow's vault...");
+        // NOTE Token-2022 requires some extensions (e.g., for transfer fees) on every token
+        // account of a mint that uses them, so the vault may need more space.
+        let vault_len = token::account_len(token_program.key, &mint.data.borrow())?;
+        Self::create_pda_account(
+            initializer,
+            vault,
+            rent.minimum_balance(vault_len),
+            vault_len,
+            token_program.key,
+            system_program,
+            vault_signer_seeds,
+        )?;
+
+        // Initialize the vault as a token account for the offered mint, owned by the PDA.
+        // NOTE initialize_account2 takes the owner in the instruction data, so we don't
+        // need to pass the PDA's AccountInfo.
+        let init_vault_ix = token::initialize_account2(
+            token_program.key,
+            vault.key, // the vault
+            mint.key, // mint of the offered X token
+            pda, // owner of the vault (PDA)
+        )?;
+        msg!("Calling the token program to initialize the escrow's vault...");
+        invoke(
+            &init_vault_ix,
+            &[
+                vault.clone(),
+                mint.clone(),
+                rent_sysvar.clone(),
+                token_program.clone(),
+            ],
+        )?;
+
+        // Finally move Alice's X tokens into the vault.
+        // NOTE We're using the Signature Extension concept here:
+        // https://docs.solana.com/developing/programming-model/calling-between-programs#instructions-that-require-privileges
+        // "When including a signed account in a program call, in all CPIs including that
+        // account made by the program inside the current instruction, the account will
+        // also be signed, i.e., the signature is extended to the CPIs."
+        // NOTE This means that since Alice signed the InitEscrow transaction, the program
+        // can make the token program transfer CPI and include her pubkey as a signer pubkey.
+        // This is necessary because moving tokens out of a token account should of course
+        // require the approval of its owner.
+        // IMPORTANT By token program we mean the spl_token program, which has its own
+        // instruction.rs:
+        // https://docs.rs/spl-token/2.0.4/src/spl_token/instruction.rs.html#538-550
+        let transfer_fee = token::transfer_fee(&mint.data.borrow(), Clock::get()?.epoch)?;
+        let deposit_ix = token::transfer_checked(
+            token_program.key, // token program id
+            initializers_sending_token_account.key, // source (Alice's X token account)
+            mint.key, // mint of the offered X token
+            vault.key, // destination (the vault)
+            initializer.key, // authority_pubkey (Alice -> initializer.key)
+            &[initializer.key], // public keys signing the CPI
+            transfer_fee
+                .calculate_pre_fee_amount(amount)
+                .ok_or(EscrowError::AmountOverflow)?,
+            decimals,
+        )?;
+
+        msg!("Calling the token program to deposit the offered tokens into the vault...");
+        invoke(
+            &deposit_ix, // The Cross-Program Instruction
+            &[
+                initializers_sending_token_account.clone(), // Accounts required by the CPI instruction
+                mint.clone(), // Accounts required by the CPI instruction
+                vault.clone(), // Accounts required by the CPI instruction
+                initializer.clone(), // Accounts required by the CPI instruction
+                token_program.clone(), // Account of the program we're calling
+            ], 
+        )?;
+
+        // IMPORTANT: Don't just trust our fee math here either. The vault was empty, so it
+        // has to hold at least `amount` tokens now, or Bob would be promised tokens that
+        // aren't there (same check as in Exchange).
+        if token::unpack_account(&vault.data.borrow())?.amount < amount {
+            return Err(EscrowError::ReceivedAmountTooLow.into());
+        }
+        Ok(())
+    }
+
+    // Refunds the offered side of an escrow: what's left in the PDA's temp token account
+    // goes to the given token account and the temp account's rent to rent_destination.
+    // When the initializer offered native SOL, the vault's lamports (offer and rent) all
+    // go to rent_destination instead.
+    // NOTE When associated_token_accounts is passed in, the refund token account is the
+    // initializer's associated token account, created if she doesn't have it anymore.
+    #[allow(clippy::too_many_arguments)]
+    fn refund_offered_side<'a>(
+        escrow_info: &Escrow,
+        pdas_temp_token_account: &AccountInfo<'a>,
+        offered_mint_account: Option<&AccountInfo<'a>>,
+        refund_token_account: &AccountInfo<'a>,
+        rent_destination: &AccountInfo<'a>,
+        token_program: &AccountInfo<'a>,
+        pda_account: &AccountInfo<'a>,
+        pda_signer_seeds: &[&[u8]],
+        associated_token_accounts: Option<&AssociatedTokenAccounts<'a, '_>>,
+    ) -> ProgramResult {
+        if escrow_info.offered_kind == AssetKind::Native {
+            msg!("Returning lamports from the escrow's vault to the initializer...");
+            return Self::transfer_lamports(pdas_temp_token_account, rent_destination, pdas_temp_token_account.lamports());
+        }
+
+        // Only a native offer comes without a mint
+        let offered_mint_account = offered_mint_account.ok_or(EscrowError::OfferedMintMismatch)?;
+        if let Some(associated_token_accounts) = associated_token_accounts {
+            associated_token_accounts.create_idempotent(refund_token_account, offered_mint_account, token_program)?;
+        }
+        Self::release_token_vault(
+            pdas_temp_token_account,
+            offered_mint_account,
+            escrow_info.offered_decimals,
+            refund_token_account,
+            rent_destination,
+            token_program,
+            pda_account,
+            pda_signer_seeds,
+        )
+    }
+
+    // Refunds the extra offered tokens of a basket escrow. `leg_accounts` holds a
+    // (vault, refund token account, mint) triple for every leg, in the escrow's order.
+    // NOTE When the initializer isn't signing (RefundExpired), the refund token accounts
+    // must belong to her, otherwise whoever calls this could keep the tokens.
+    // NOTE Like in refund_offered_side, associated_token_accounts creates her associated
+    // token accounts first (when passed in), so the checks below find them in place.
+    #[allow(clippy::too_many_arguments)]
+    fn refund_basket<'a>(
+        escrow_info: &Escrow,
+        leg_accounts: &[AccountInfo<'a>],
+        rent_destination: &AccountInfo<'a>,
+        token_program: &AccountInfo<'a>,
+        pda_account: &AccountInfo<'a>,
+        pda_signer_seeds: &[&[u8]],
+        check_refund_owner: bool,
+        associated_token_accounts: Option<&AssociatedTokenAccounts<'a, '_>>,
+    ) -> ProgramResult {
+        if leg_accounts.len() < escrow_info.basket_offered.len() * 3 {
+            return Err(ProgramError::NotEnoughAccountKeys);
+        }
+        for (i, (leg, leg_accounts)) in escrow_info.basket_offered.iter().zip(leg_accounts.chunks_exact(3)).enumerate() {
+            let (leg_vault, leg_refund_token_account, leg_mint_account) = (&leg_accounts[0], &leg_accounts[1], &leg_accounts[2]);
+            assert_address(leg_vault, &leg.account, EscrowError::InvalidVaultAddress)?;
+            assert_address(leg_mint_account, &leg.mint, EscrowError::OfferedMintMismatch)?;
+            assert_writable(leg_refund_token_account)?;
+            if let Some(associated_token_accounts) = associated_token_accounts {
+                associated_token_accounts.create_idempotent(leg_refund_token_account, leg_mint_account, token_program)?;
+            }
+            let leg_refund_token_account_info = unpack_token_account(leg_refund_token_account, token_program.key)?;
+            if check_refund_owner && leg_refund_token_account_info.owner != escrow_info.initializer_pubkey {
+                return Err(EscrowError::InvalidRefundAccount.into());
+            }
+            if leg_refund_token_account_info.mint != leg.mint {
+                return Err(EscrowError::OfferedMintMismatch.into());
+            }
+            Self::release_token_vault(
+                leg_vault,
+                leg_mint_account,
+                escrow_info.basket_offered_decimals(i)?,
+                leg_refund_token_account,
+                rent_destination,
+                token_program,
+                pda_account,
+                pda_signer_seeds,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    // Settles the extra tokens of a basket escrow in Exchange. `leg_accounts` holds a
+    // (taker's sending token account, initializer's receiving token account, fee recipient,
+    // mint) group for every requested leg, preceded by a (vault, taker's receiving token
+    // account, mint) triple for every offered leg, each in the escrow's order.
+    // NOTE The legs of each side live in that side's token program.
+    // NOTE When associated_token_accounts is passed in, the taker's receiving token accounts
+    // are his associated token accounts, created if he doesn't have them yet.
+    #[allow(clippy::too_many_arguments)]
+    fn settle_basket<'a>(
+        escrow_info: &Escrow,
+        leg_accounts: &[AccountInfo<'a>],
+        taker: &AccountInfo<'a>,
+        initializers_main_account: &AccountInfo<'a>,
+        config: &Config,
+        offered_token_program: &AccountInfo<'a>,
+        requested_token_program: &AccountInfo<'a>,
+        pda_account: &AccountInfo<'a>,
+        pda_signer_seeds: &[&[u8]],
+        associated_token_accounts: Option<&AssociatedTokenAccounts<'a, '_>>,
+    ) -> ProgramResult {
+        let offered_leg_accounts_len = escrow_info.basket_offered.len() * 3;
+        if leg_accounts.len() < offered_leg_accounts_len + escrow_info.basket_requested.len() * 4 {
+            return Err(ProgramError::NotEnoughAccountKeys);
+        }
+        let (offered_leg_accounts, requested_leg_accounts) = leg_accounts.split_at(offered_leg_accounts_len);
+        let epoch = Clock::get()?.epoch;
+
+        // Bob pays Alice (minus the protocol fee) for every extra token she requested...
+        // NOTE Just like for the first requested token, Bob pays any transfer fee on top.
+        for (i, (leg, leg_accounts)) in escrow_info.basket_requested.iter().zip(requested_leg_accounts.chunks_exact(4)).enumerate() {
+            let (takers_sending_token_account, initializers_token_to_receive_account, fee_recipient_account, leg_mint_account) =
+                (&leg_accounts[0], &leg_accounts[1], &leg_accounts[2], &leg_accounts[3]);
+            assert_writable(takers_sending_token_account)?;
+            assert_writable(initializers_token_to_receive_account)?;
+            assert_writable(fee_recipient_account)?;
+            assert_address(initializers_token_to_receive_account, &leg.account, EscrowError::ReceiveAccountMismatch)?;
+            assert_address(leg_mint_account, &leg.mint, EscrowError::RequestedMintMismatch)?;
+            if unpack_token_account(takers_sending_token_account, requested_token_program.key)?.mint != leg.mint {
+                return Err(EscrowError::RequestedMintMismatch.into());
+            }
+            let fee_recipient_account_info = unpack_token_account(fee_recipient_account, requested_token_program.key)?;
+            if fee_recipient_account_info.owner != config.treasury || fee_recipient_account_info.mint != leg.mint {
+                return Err(EscrowError::FeeRecipientMismatch.into());
+            }
+
+            let leg_decimals = escrow_info.basket_requested_decimals(i)?;
+            let transfer_fee = token::transfer_fee(&leg_mint_account.data.borrow(), epoch)?;
+            let with_transfer_fee = |amount: u64| {
+                transfer_fee
+                    .calculate_pre_fee_amount(amount)
+                    .ok_or(EscrowError::AmountOverflow)
+            };
+            let fee_amount = config.fee_for(leg.amount).ok_or(EscrowError::AmountOverflow)?;
+            let initializer_amount = leg.amount.checked_sub(fee_amount).ok_or(EscrowError::AmountOverflow)?;
+            if fee_amount > 0 {
+                Self::transfer_tokens(
+                    requested_token_program,
+                    takers_sending_token_account,
+                    leg_mint_account,
+                    fee_recipient_account,
+                    taker,
+                    with_transfer_fee(fee_amount)?,
+                    leg_decimals,
+                )?;
+            }
+            let initializer_balance_before = token::unpack_account(&initializers_token_to_receive_account.data.borrow())?.amount;
+            Self::transfer_tokens(
+                requested_token_program,
+                takers_sending_token_account,
+                leg_mint_account,
+                initializers_token_to_receive_account,
+                taker,
+                with_transfer_fee(initializer_amount)?,
+                leg_decimals,
+            )?;
+            let initializer_balance_after = token::unpack_account(&initializers_token_to_receive_account.data.borrow())?.amount;
+            if initializer_balance_after.saturating_sub(initializer_balance_before) < initializer_amount {
+                return Err(EscrowError::ReceivedAmountTooLow.into());
+            }
+        }
+
+        // ...and receives everything in the vaults of the extra tokens she offered. The
+        // vaults' rent goes back to Alice.
+        for (i, (leg, leg_accounts)) in escrow_info.basket_offered.iter().zip(offered_leg_accounts.chunks_exact(3)).enumerate() {
+            let (leg_vault, takers_receiving_token_account, leg_mint_account) = (&leg_accounts[0], &leg_accounts[1], &leg_accounts[2]);
+            assert_address(leg_vault, &leg.account, EscrowError::InvalidVaultAddress)?;
+            assert_address(leg_mint_account, &leg.mint, EscrowError::OfferedMintMismatch)?;
+            assert_writable(takers_receiving_token_account)?;
+            if let Some(associated_token_accounts) = associated_token_accounts {
+                associated_token_accounts.create_idempotent(takers_receiving_token_account, leg_mint_account, offered_token_program)?;
+            } else if unpack_token_account(takers_receiving_token_account, offered_token_program.key)?.mint != leg.mint {
+                return Err(EscrowError::OfferedMintMismatch.into());
+            }
+            Self::release_token_vault(
+                leg_vault,
+                leg_mint_account,
+                escrow_info.basket_offered_decimals(i)?,
+                takers_receiving_token_account,
+                initializers_main_account,
+                offered_token_program,
+                pda_account,
+                pda_signer_seeds,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    // Grabs the mint of the offered token, unless native SOL was offered.
+    // NOTE The token program needs the mint for transfer_checked. With Token-2022 the vault's
+    // withheld transfer fees may also have to be harvested into it before the vault can be
+    // closed, so only then does the mint have to be writable.
+    fn next_offered_mint_account<'a, 'b>(
+        escrow_info: &Escrow,
+        account_info_iter: &mut std::slice::Iter<'b, AccountInfo<'a>>,
+    ) -> Result<Option<&'b AccountInfo<'a>>, ProgramError> {
+        if escrow_info.offered_kind == AssetKind::Native {
+            return Ok(None);
+        }
+        let offered_mint_account = next_account_info(account_info_iter)?;
+        if escrow_info.offered_token_program == TokenProgram::Token2022 {
+            assert_writable(offered_mint_account)?;
+        }
+        assert_address(offered_mint_account, &escrow_info.offered_mint, EscrowError::OfferedMintMismatch)?;
+        Ok(Some(offered_mint_account))
+    }
+
+    // Grabs the system and Associated Token Account programs needed to create the wallet's
+    // associated token accounts (paid for by payer)
+    fn next_associated_token_accounts<'a, 'b>(
+        payer: &'b AccountInfo<'a>,
+        wallet: &'b AccountInfo<'a>,
+        account_info_iter: &mut std::slice::Iter<'b, AccountInfo<'a>>,
+    ) -> Result<AssociatedTokenAccounts<'a, 'b>, ProgramError> {
+        let system_program = next_account_info(account_info_iter)?;
+        assert_program(system_program, &solana_program::system_program::id())?;
+        let associated_token_program = next_account_info(account_info_iter)?;
+        assert_program(associated_token_program, &token::spl_associated_token_account::id())?;
+        Ok(AssociatedTokenAccounts {
+            payer,
+            wallet,
+            system_program,
+            associated_token_program,
+        })
+    }
+
+    // Transfers tokens out of a token account whose owner signed the transaction
+    // (Signature Extension, see open_token_vault).
+    #[allow(clippy::too_many_arguments)]
+    fn transfer_tokens<'a>(
+        token_program: &AccountInfo<'a>,
+        source: &AccountInfo<'a>,
+        mint: &AccountInfo<'a>,
+        destination: &AccountInfo<'a>,
+        authority: &AccountInfo<'a>,
+        amount: u64,
+        decimals: u8,
+    ) -> ProgramResult {
+        let transfer_ix = token::transfer_checked(
+            token_program.key,
+            source.key,
+            mint.key,
+            destination.key,
+            authority.key,
+            &[authority.key],
+            amount,
+            decimals,
+        )?;
+        msg!("Calling the token program to transfer tokens...");
+        invoke(
+            &transfer_ix,
+            &[
+                source.clone(),
+                mint.clone(),
+                destination.clone(),
+                authority.clone(),
+                token_program.clone(),
+            ],
+        )
+    }
+
+    // Transfers everything in one of the escrow's vaults to the given token account,
+    // then closes the vault and sends its rent to rent_destination.
+    // NOTE Just like in process_exchange, the PDA has to "sign" via invoke_signed
+    // since it's the vault's authority.
+    #[allow(clippy::too_many_arguments)]
+    fn release_token_vault<'a>(
+        vault: &AccountInfo<'a>,
+        mint: &AccountInfo<'a>,
+        decimals: u8,
+        destination_token_account: &AccountInfo<'a>,
+        rent_destination: &AccountInfo<'a>,
+        token_program: &AccountInfo<'a>,
+        pda_account: &AccountInfo<'a>,
+        pda_signer_seeds: &[&[u8]],
+    ) -> ProgramResult {
+        let amount = token::unpack_account(&vault.data.borrow())?.amount;
+        let transfer_from_vault_ix = token::transfer_checked(
+            token_program.key,
+            vault.key, // source (PDA's temp X token account)
+            mint.key, // mint of the vault's token
+            destination_token_account.key, // destination
+            pda_account.key, // authority_pubkey
+            &[pda_account.key], // signers array
+            amount, // everything that's left in the vault
+            decimals,
+        )?;
+        msg!("Calling the token program to transfer tokens out of the escrow's vault...");
+        invoke_signed(
+            &transfer_from_vault_ix,
+            &[
+                vault.clone(),
+                mint.clone(),
+                destination_token_account.clone(),
+                pda_account.clone(),
+                token_program.clone(),
+            ],
+            &[pda_signer_seeds],
+        )?;
+
+        // Close the now empty vault and send its rent to Alice
+        // NOTE First harvest the transfer fees Token-2022 withheld in the vault (see process_exchange)
+        if token::withheld_amount(&vault.data.borrow())? > 0 {
+            let harvest_ix = token::harvest_withheld_tokens_to_mint(token_program.key, mint.key, &[vault.key])?;
+            msg!("Calling the token program to harvest the vault's withheld transfer fees...");
+            invoke(&harvest_ix, &[mint.clone(), vault.clone(), token_program.clone()])?;
+        }
+        let close_vault_ix = token::close_account(
+            token_program.key,
+            vault.key,
+            rent_destination.key,
+            pda_account.key,
+            &[pda_account.key]
+        )?;
+        msg!("Calling the token program to close the escrow's vault...");
+        invoke_signed(
+            &close_vault_ix,
+            &[
+                vault.clone(),
+                rent_destination.clone(),
+                pda_account.clone(),
+                token_program.clone(),
+            ],
+            &[pda_signer_seeds],
+        )
+    }
+
+    // Moves lamports out of an account owned by this program (e.g., the escrow's vault
+    // holding native SOL). NOTE Only the owner of an account may debit it, but anybody
+    // may credit any account, so no CPI is needed here.
+    fn transfer_lamports(
+        from: &AccountInfo,
+        to: &AccountInfo,
+        amount: u64,
+    ) -> ProgramResult {
+        **from.lamports.borrow_mut() = from
+            .lamports()
+            .checked_sub(amount)
+            .ok_or(ProgramError::InsufficientFunds)?;
+        **to.lamports.borrow_mut() = to
+            .lamports()
+            .checked_add(amount)
+            .ok_or(EscrowError::AmountOverflow)?;
+
+        Ok(())
+    }
+
+    // Creates an account at a PDA address that's owned by `owner`, funded by `payer`.
+    // NOTE If somebody already sent lamports to the PDA address, create_account would
+    // fail. So (like the Associated Token Account Program does) we then top up the
+    // balance and allocate and assign the account ourselves instead.
+    fn create_pda_account<'a>(
+        payer: &AccountInfo<'a>,
+        new_account: &AccountInfo<'a>,
+        lamports: u64,
+        space: usize,
+        owner: &Pubkey,
+        system_program: &AccountInfo<'a>,
+        new_account_signer_seeds: &[&[u8]],
+    ) -> ProgramResult {
+        if new_account.lamports() == 0 {
+            return invoke_signed(
+                &system_instruction::create_account(
+                    payer.key,
+                    new_account.key,
+                    lamports,
+                    space as u64,
+                    owner,
+                ),
+                &[payer.clone(), new_account.clone(), system_program.clone()],
+                &[new_account_signer_seeds],
+            );
+        }
+
+        let top_up_lamports = lamports.saturating_sub(new_account.lamports());
+        if top_up_lamports > 0 {
+            invoke(
+                &system_instruction::transfer(payer.key, new_account.key, top_up_lamports),
+                &[payer.clone(), new_account.clone(), system_program.clone()],
+            )?;
+        }
+        invoke_signed(
+            &system_instruction::allocate(new_account.key, space as u64),
+            &[new_account.clone(), system_program.clone()],
+            &[new_account_signer_seeds],
         )?;
+        invoke_signed(
+            &system_instruction::assign(new_account.key, owner),
+            &[new_account.clone(), system_program.clone()],
+            &[new_account_signer_seeds],
+        )
+    }
 
-        // 10. Time to close the Escrow (state) account to conclude this program
+    // Closes the Escrow (state) account once a trade is over (taken, cancelled or refunded),
+    // sending its rent to rent_destination (Alice's main account)
+    fn close_escrow_account(
+        escrow_account: &AccountInfo,
+        rent_destination: &AccountInfo,
+    ) -> ProgramResult {
         msg!("Closing the escrow account...");
         // We can credit Alice's main account with remaining balance in escrow account
         // NOTE You can credit her account even though Escrow Program isn't the owner
         // of her (initializer's) account.
-        **initializers_main_account.lamports.borrow_mut() = initializers_main_account
+        **rent_destination.lamports.borrow_mut() = rent_destination
             .lamports()
             .checked_add(escrow_account.lamports())
-            .ok_or(EscrowError::AmountOverflow)?; // Need to add this new error in error.rs
+            .ok_or(EscrowError::AmountOverflow)?;
         **escrow_account.lamports.borrow_mut() = 0;
         *escrow_account.data.borrow_mut() = &mut []; // Set the 'data' field to empty slice
         // NOTE Even though the account will be purged at runtime, this is not the final
@@ -438,4 +2139,64 @@ impl Processor {
 
         Ok(())
     }
+
+    // Recreates the PDA that owns a given escrow's temp token account.
+    // NOTE create_program_address() only hashes once with the bump we saved in
+    // the escrow state, whereas find_program_address() loops until it finds a bump.
+    fn escrow_pda(
+        escrow_account_key: &Pubkey,
+        bump_seed: u8,
+        program_id: &Pubkey,
+    ) -> Result<Pubkey, ProgramError> {
+        Pubkey::create_program_address(
+            &[&b"escrow"[..], escrow_account_key.as_ref(), &[bump_seed]],
+            program_id,
+        )
+        .map_err(|_| EscrowError::InvalidPdaAccount.into())
+    }
+}
+
+// The accounts needed to create a wallet's associated token accounts (see create_idempotent)
+struct AssociatedTokenAccounts<'a, 'b> {
+    payer: &'b AccountInfo<'a>,
+    wallet: &'b AccountInfo<'a>,
+    system_program: &'b AccountInfo<'a>,
+    associated_token_program: &'b AccountInfo<'a>,
+}
+
+impl<'a, 'b> AssociatedTokenAccounts<'a, 'b> {
+    // Creates the wallet's associated token account for the mint, unless it already exists.
+    // IMPORTANT: token_account must be the canonical associated token account, otherwise
+    // anybody could pass in an account of their own and claim it's the wallet's.
+    // NOTE If the account already exists, the ATA program checks it still belongs to the
+    // wallet (SPL Token accounts can change owners), so the tokens can't end up elsewhere.
+    fn create_idempotent(
+        &self,
+        token_account: &AccountInfo<'a>,
+        mint: &AccountInfo<'a>,
+        token_program: &AccountInfo<'a>,
+    ) -> ProgramResult {
+        let associated_token_address = token::get_associated_token_address(self.wallet.key, mint.key, token_program.key);
+        assert_address(token_account, &associated_token_address, EscrowError::InvalidAssociatedTokenAccount)?;
+
+        let create_ix = token::create_associated_token_account_idempotent(
+            self.payer.key,
+            self.wallet.key,
+            mint.key,
+            token_program.key,
+        );
+        msg!("Calling the associated token account program to create the associated token account...");
+        invoke(
+            &create_ix,
+            &[
+                self.payer.clone(),
+                token_account.clone(),
+                self.wallet.clone(),
+                mint.clone(),
+                self.system_program.clone(),
+                token_program.clone(),
+                self.associated_token_program.clone(),
+            ],
+        )
+    }
 }
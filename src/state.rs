@@ -1,14 +1,101 @@
 // Responsible for defining state objects that the processor can use,
 // and responsible for serializing/deserializing such objects from/into
 // arrays of u8.
+// NOTE We (de)serialize with Borsh (https://borsh.io), which is also what most Solana
+// clients use. The BorshSchema derive lets clients in other languages generate the layout.
+use borsh::{BorshDeserialize, BorshSchema, BorshSerialize};
+
 use solana_program::{
+    borsh::try_from_slice_unchecked,
     program_pack::{IsInitialized, Pack, Sealed},
     program_error::ProgramError,
     pubkey::Pubkey,
 };
 
-use arrayref::{array_mut_ref, array_ref, array_refs, mut_array_refs};
+use std::{convert::TryFrom, iter};
+
+use crate::{error::EscrowError, token::spl_token_2022};
+
+/// The first byte of every account this program stores state in, saying what kind of
+/// account it is. Without it, e.g., a Config account could be passed in as an Escrow
+/// (both are owned by this program) and be read as if it were one.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AccountType {
+    /// A freshly created account that hasn't been written yet (all zeros)
+    Uninitialized = 0,
+    /// An Escrow account
+    Escrow = 1,
+    /// The program's Config account
+    Config = 2,
+}
+
+impl AccountType {
+    // Strips the discriminator off an account's data, making sure the account is of
+    // this type (or still brand new).
+    fn strip(self, src: &[u8]) -> Result<&[u8], ProgramError> {
+        let (account_type, src) = src.split_first().ok_or(ProgramError::InvalidAccountData)?;
+        if *account_type != self as u8 && *account_type != AccountType::Uninitialized as u8 {
+            return Err(EscrowError::InvalidAccountType.into());
+        }
+        Ok(src)
+    }
+}
+
+/// Whether one side of a trade is an SPL token or native SOL (lamports)
+#[derive(BorshSerialize, BorshDeserialize, BorshSchema, Clone, Copy, Debug, PartialEq)]
+pub enum AssetKind {
+    /// An SPL token held in a token account
+    Token,
+    /// Native SOL held as lamports
+    Native,
+    /// A single NFT, i.e., one unit of an SPL token mint with a supply of 1, 0 decimals
+    /// and no mint authority
+    Nft,
+}
+
+/// Which token program the tokens of one side of a trade belong to
+#[derive(BorshSerialize, BorshDeserialize, BorshSchema, Clone, Copy, Debug, PartialEq)]
+pub enum TokenProgram {
+    /// The original SPL Token program
+    SplToken,
+    /// The Token-2022 program
+    Token2022,
+}
+
+impl TokenProgram {
+    /// The program id of the token program
+    pub fn id(&self) -> Pubkey {
+        match self {
+            TokenProgram::SplToken => spl_token::id(),
+            TokenProgram::Token2022 => spl_token_2022::id(),
+        }
+    }
+
+    /// The token program with the given program id, if it is one
+    pub fn from_id(program_id: &Pubkey) -> Option<Self> {
+        if *program_id == spl_token::id() {
+            Some(TokenProgram::SplToken)
+        } else if *program_id == spl_token_2022::id() {
+            Some(TokenProgram::Token2022)
+        } else {
+            None
+        }
+    }
+}
+
+/// One extra token of a basket trade, on top of the escrow's offered/requested token
+#[derive(BorshSerialize, BorshDeserialize, BorshSchema, Clone, Debug, PartialEq)]
+pub struct BasketLeg {
+    /// The mint of the token
+    pub mint: Pubkey,
+    /// How many tokens of the mint change hands
+    pub amount: u64,
+    /// For an offered leg, the leg's vault (PDA `[b"vault", escrow_account, mint]`).
+    /// For a requested leg, the initializer's token account receiving the tokens.
+    pub account: Pubkey,
+}
 
+#[derive(BorshSerialize, BorshDeserialize, BorshSchema, Debug, PartialEq)]
 pub struct Escrow {
     // Determine whether a given escrow account is already in use. This, serialization,
     // and deserialization are all standardized in the traits of the program_pack module.
@@ -17,7 +104,8 @@ pub struct Escrow {
     pub initializer_pubkey: Pubkey,
     // Save temp_token_account_pubkey so that when Bob takes the trade,
     // the escrow program can send tokens from the account at temp_token_account_pubkey
-    // to Bob's account. I believe this is Alice's temp_token_account.
+    // to Bob's account. This is the escrow's vault, i.e., the temp token account
+    // the program created at the `[b"vault", escrow_account]` PDA during InitEscrow.
     // IMPORTANT:
     // Q: Why save this account address here?
     // A: Bob will have to pass in the account into his entrypoint call eventually,
@@ -38,6 +126,54 @@ pub struct Escrow {
     pub initializer_token_to_receive_account_pubkey: Pubkey,
     // Save expected_amount so we can use it to check that Bob sends enough tokens.
     pub expected_amount: u64,
+    // Save the bump seed of this escrow's PDA (seeded with the escrow account's key),
+    // so we can recreate the PDA with create_program_address() later on instead of
+    // calling find_program_address() in every Exchange/Cancel.
+    pub bump_seed: u8,
+    // Save the (optional) unix timestamp after which Bob can no longer take the trade
+    // and anybody may crank a refund back to Alice.
+    pub expires_at: Option<i64>,
+    // Save how many X tokens Alice deposited and how many are still up for grabs,
+    // so Bob (and others) can take the offer in several partial fills.
+    pub offered_amount: u64,
+    pub remaining_amount: u64,
+    // Save the mints of both sides of the trade, so Exchange can check that every
+    // token account passed in holds the right token.
+    pub offered_mint: Pubkey,
+    pub requested_mint: Pubkey,
+    // Save whether each side of the trade is an SPL token or native SOL.
+    // NOTE For a native SOL side the mint above is left as Pubkey::default().
+    pub offered_kind: AssetKind,
+    pub requested_kind: AssetKind,
+    // Save the nonce the escrow account's address was derived from (together with
+    // initializer_pubkey), so clients can re-derive it.
+    pub nonce: u64,
+    // Save the (optional) taker Alice negotiated the trade with off-chain. When set,
+    // nobody but this taker can call Exchange on the escrow.
+    pub allowed_taker: Option<Pubkey>,
+    // Save the (optional) root of a Merkle tree of wallets allowed to take the trade,
+    // for private sales to more takers than we could ever store in this account.
+    pub taker_merkle_root: Option<[u8; 32]>,
+    // Save the extra tokens of a basket trade (e.g., three tokens for one). Each offered
+    // leg sits in its own vault, and Exchange settles all legs at once.
+    // NOTE Borsh writes a Vec as a u32 length followed by its elements.
+    pub basket_offered: Vec<BasketLeg>,
+    pub basket_requested: Vec<BasketLeg>,
+    // Save the (optional) collection the offered NFT must belong to. InitEscrow checked
+    // the NFT's Metaplex metadata against it, so takers can rely on it.
+    pub offered_collection: Option<Pubkey>,
+    // Save which token program each side's tokens (including the basket legs) belong
+    // to, so Exchange and the refunds call the right one. Native SOL sides use SplToken.
+    pub offered_token_program: TokenProgram,
+    pub requested_token_program: TokenProgram,
+    // Save the decimals of every token of the trade, which we pass to transfer_checked so
+    // the token program rejects a mint with different decimals than the one agreed upon.
+    // NOTE The basket legs' decimals are kept in the same order as the legs themselves.
+    // Native SOL sides are left at 0.
+    pub offered_decimals: u8,
+    pub requested_decimals: u8,
+    pub basket_offered_decimals: Vec<u8>,
+    pub basket_requested_decimals: Vec<u8>,
 }
 
 // Implement Sealed and IsInitialized from program_pack to help determine
@@ -47,12 +183,191 @@ pub struct Escrow {
 // NOTE Pack relies on Sealed and, in our case, also on IsInitialized being implemented.
 impl Sealed for Escrow {}
 
+// The original escrow layout from before we versioned the account data, i.e., before
+// expiry, partial fills, mints, etc. Such accounts are exactly LegacyEscrow::LEN bytes
+// long and have no version byte. Their vault is owned by the single PDA `[b"escrow"]`.
+// NOTE We only keep this around so MigrateEscrow can read old accounts. Every other
+// instruction asks for the latest layout.
+// NOTE Legacy accounts predate the AccountType discriminator as well, so we can only
+// tell them apart by their length (no other account of ours is LegacyEscrow::LEN long).
+#[derive(BorshSerialize, BorshDeserialize, BorshSchema, Debug, PartialEq)]
+pub struct LegacyEscrow {
+    pub is_initialized: bool,
+    pub initializer_pubkey: Pubkey,
+    pub temp_token_account_pubkey: Pubkey,
+    pub initializer_token_to_receive_account_pubkey: Pubkey,
+    pub expected_amount: u64,
+}
+
+impl LegacyEscrow {
+    // 1 (bool) + 3 * 32 (Pubkey) + 8 (u64) = 105
+    pub const LEN: usize = 105;
+
+    /// Decodes a legacy escrow account. NOTE The Borsh layout of this struct is exactly
+    /// the old hand-packed one.
+    pub fn unpack(src: &[u8]) -> Result<Self, ProgramError> {
+        if src.len() != Self::LEN {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        Self::try_from_slice(src).map_err(|_| ProgramError::InvalidAccountData)
+    }
+}
+
 impl IsInitialized for Escrow {
     fn is_initialized(&self) -> bool {
         self.is_initialized
     }
 }
 
+impl Escrow {
+    /// The layout version written in the first byte of every escrow account.
+    /// Version 1 is the LegacyEscrow layout, which didn't have a version byte yet.
+    /// NOTE Bump this whenever the fields change and teach MigrateEscrow how to
+    /// upgrade the previous version.
+    pub const VERSION: u8 = 2;
+
+    /// The most extra tokens a basket trade can have on each side
+    pub const MAX_BASKET_LEGS: usize = 3;
+
+    /// Whether this is a basket trade, i.e., more than one token changes hands on a side
+    pub fn is_basket(&self) -> bool {
+        !self.basket_offered.is_empty() || !self.basket_requested.is_empty()
+    }
+
+    /// The decimals of the offered basket leg at `index`
+    pub fn basket_offered_decimals(&self, index: usize) -> Result<u8, ProgramError> {
+        self.basket_offered_decimals.get(index).copied().ok_or(ProgramError::InvalidAccountData)
+    }
+
+    /// The decimals of the requested basket leg at `index`
+    pub fn basket_requested_decimals(&self, index: usize) -> Result<u8, ProgramError> {
+        self.basket_requested_decimals.get(index).copied().ok_or(ProgramError::InvalidAccountData)
+    }
+
+    /// Whether every token of the trade, on either side, has its own mint.
+    /// NOTE Otherwise the legs of a basket trade couldn't be told apart.
+    pub fn has_unique_mints(&self) -> bool {
+        let mints: Vec<&Pubkey> = iter::once(&self.offered_mint)
+            .chain(self.basket_offered.iter().map(|leg| &leg.mint))
+            .chain(iter::once(&self.requested_mint))
+            .chain(self.basket_requested.iter().map(|leg| &leg.mint))
+            .collect();
+        mints
+            .iter()
+            .enumerate()
+            .all(|(i, mint)| !mints[..i].contains(mint))
+    }
+
+    /// Whether the trade's deadline (if any) has passed at the given unix timestamp
+    pub fn is_expired(&self, unix_timestamp: i64) -> bool {
+        match self.expires_at {
+            Some(expires_at) => unix_timestamp >= expires_at,
+            None => false,
+        }
+    }
+
+    /// The amount of the requested token a taker owes for taking `fill_amount` of the
+    /// offered token, given the fills that already happened.
+    ///
+    /// The price is `expected_amount / offered_amount`. Rounding happens on the running
+    /// total (and always up), so the initializer is never underpaid for a partial fill
+    /// and the fills of a completely taken offer add up to exactly `expected_amount`.
+    pub fn payment_for_fill(&self, fill_amount: u64) -> Option<u64> {
+        let filled_before = self.offered_amount.checked_sub(self.remaining_amount)?;
+        let filled_after = filled_before.checked_add(fill_amount)?;
+        if filled_after > self.offered_amount {
+            return None;
+        }
+        let owed_before = self.owed_for(filled_before)?;
+        let owed_after = self.owed_for(filled_after)?;
+        owed_after.checked_sub(owed_before)
+    }
+
+    /// The amount of the requested token the takers paid for the fills so far
+    pub fn paid_amount(&self) -> Option<u64> {
+        self.owed_for(self.offered_amount.checked_sub(self.remaining_amount)?)
+    }
+
+    // ceil(filled * expected_amount / offered_amount), using u128 to avoid overflowing
+    fn owed_for(&self, filled: u64) -> Option<u64> {
+        let numerator = (filled as u128).checked_mul(self.expected_amount as u128)?;
+        let offered = self.offered_amount as u128;
+        let owed = numerator.checked_add(offered.checked_sub(1)?)? / offered;
+        u64::try_from(owed).ok()
+    }
+}
+
+// Program-wide settings, stored in a single account at the PDA `[b"config"]`.
+// NOTE Unlike the escrow accounts there's only ONE config account per program, so
+// Exchange can always find it by derivation.
+// NOTE The settings can be changed by the admin with UpdateConfig, so we don't
+// have to redeploy the program to e.g. change the fee.
+#[derive(BorshSerialize, BorshDeserialize, BorshSchema, Debug, PartialEq)]
+pub struct Config {
+    pub is_initialized: bool,
+    // Save the authority allowed to update the config and hand it over to somebody else.
+    pub admin: Pubkey,
+    // Save the protocol fee taken from every exchange's payment, in basis points
+    // (1 bps = 0.01%, so 10_000 bps = 100%), at most MAX_FEE_BPS.
+    pub fee_bps: u16,
+    // Save the wallet collecting the fees. Fees in a token are paid into one of
+    // the treasury's token accounts for that mint, fees in native SOL straight into it.
+    pub treasury: Pubkey,
+    // Save whether the program is paused. While paused, nobody can open new escrows
+    // or take existing ones, but initializers can still get their funds back.
+    pub paused: bool,
+    // Save the bump seed of the config PDA so we can check its address cheaply.
+    pub bump_seed: u8,
+}
+
+impl Sealed for Config {}
+
+impl IsInitialized for Config {
+    fn is_initialized(&self) -> bool {
+        self.is_initialized
+    }
+}
+
+impl Config {
+    /// 100% in basis points
+    pub const ONE_IN_BASIS_POINTS: u16 = 10_000;
+
+    /// The largest fee the admin can set, i.e., 10% of the payment. NOTE Even a
+    /// compromised admin can't take (nearly) everything the takers pay.
+    pub const MAX_FEE_BPS: u16 = 1_000;
+
+    /// The protocol fee owed on a payment of `amount`, rounded down
+    pub fn fee_for(&self, amount: u64) -> Option<u64> {
+        let fee = (amount as u128).checked_mul(self.fee_bps as u128)? / Self::ONE_IN_BASIS_POINTS as u128;
+        u64::try_from(fee).ok()
+    }
+
+    /// The protocol fee owed on a payment of `amount`, given that `paid_before` was paid
+    /// before it (e.g., by the earlier fills of a partially taken escrow).
+    /// NOTE fee_for rounds down, so charging every fill on its own would let many small fills
+    /// pay (next to) no fee at all. Like Escrow::payment_for_fill, we round the running total
+    /// instead, so the fills of an escrow pay the same fee as a single fill would.
+    pub fn fee_for_payment(&self, paid_before: u64, amount: u64) -> Option<u64> {
+        let paid_after = paid_before.checked_add(amount)?;
+        self.fee_for(paid_after)?.checked_sub(self.fee_for(paid_before)?)
+    }
+}
+
+impl Pack for Config {
+    // The worst case size of the config, including the AccountType in front:
+    // 1 (AccountType) + 1 (bool) + 32 (Pubkey) + 2 (u16) + 32 (Pubkey) + 1 (bool) + 1 (u8) = 70
+    const LEN: usize = 70;
+
+    fn unpack_from_slice(src: &[u8]) -> Result<Self, ProgramError> {
+        unpack_borsh(AccountType::Config.strip(src)?)
+    }
+
+    fn pack_into_slice(&self, dst: &mut [u8]) {
+        dst[0] = AccountType::Config as u8;
+        pack_borsh(self, &mut dst[1..])
+    }
+}
+
 // NOTE When implementing Pack you have to implement all of its traits
 // e.g., LEN, unpack_from_slice, pack_into_slice, etc.
 // Q: 'impl' does want exactly?
@@ -64,40 +379,36 @@ impl IsInitialized for Escrow {
 // https://docs.rs/solana-program/1.7.1/src/solana_program/program_pack.rs.html#29-39
 impl Pack for Escrow {
     // Define the escrow's length.
-    // LEN is the size of our type (Escrow). We can calculate the length of
-    // the struct by adding the sizes of the individual data types:
-    // 1 (bool) + 3 * 32 (Pubkey) + 1 * 8 (u64) = 105
-    // NOTE It's okay to use an entire u8 for the bool since it'll make our
-    // coding easier and the cost of those extra wasted bits is infinitesimal.
-    const LEN: usize = 105;
+    // LEN is the WORST CASE size of our type (Escrow), i.e., with every Option set.
+    // We can calculate the length of the struct by adding the sizes of the individual
+    // data types the way Borsh serializes them, plus the AccountType and version bytes in front:
+    // 1 (AccountType) + 1 (version) + 1 (bool) + 3 * 32 (Pubkey) + 1 * 8 (u64) + 1 (u8) + 1 + 8 (Option<i64>)
+    // + 2 * 8 (u64) + 2 * 32 (Pubkey) + 2 * 1 (AssetKind) + 8 (u64) + 33 (Option<Pubkey>)
+    // + 33 (Option<[u8; 32]>) + 2 * (4 + MAX_BASKET_LEGS * (32 + 8 + 32)) (Vec<BasketLeg>)
+    // + 33 (Option<Pubkey>) + 2 * 1 (TokenProgram) + 2 * 1 (u8) + 2 * (4 + MAX_BASKET_LEGS) (Vec<u8>) = 764
+    // NOTE Each Option takes 1 tag byte (0 = None, 1 = Some) followed by the value,
+    // but a None takes ONLY the tag byte, so the fields after it move up.
+    // NOTE solana_program::borsh::get_packed_len() can't compute this for us, since it
+    // doesn't support Vecs (or bools). The worst_case_len test checks the math instead.
+    const LEN: usize = 764;
+
     // Let's DESERIALIZE STATE using unpack_from_slice(), a static constructor function.
     // unpack_from_slice turns an array of u8 into an instance of the Escrow struct.
-    // NOTE arrayref library for getting references to SECTIONS of a slice.
-    // Need to add as dependency inside Cargo.toml.
+    // NOTE Borsh walks through the fields in the order they are declared in the struct,
+    // so there are no offsets to maintain when we add a field.
     // NOTE Self in this case is a new instance of an Escrow struct
+    // NOTE The AccountType check makes sure nobody can pass in another kind of
+    // account owned by this program (e.g., the Config) as an escrow.
     fn unpack_from_slice(src: &[u8]) -> Result<Self, ProgramError> {
-        let src = array_ref![src, 0, Escrow::LEN];
-        let (
-            is_initialized,
-            initializer_pubkey,
-            temp_token_account_pubkey,
-            initializer_token_to_receive_account_pubkey,
-            expected_amount,
-        ) = array_refs![src, 1, 32, 32, 32, 8];
-
-        let is_initialized = match is_initialized {
-            [0] => false,
-            [1] => true,
-            _ => return Err(ProgramError::InvalidAccountData),
-        };
-
-        Ok(Escrow {
-            is_initialized,
-            initializer_pubkey: Pubkey::new_from_array(*initializer_pubkey),
-            temp_token_account_pubkey: Pubkey::new_from_array(*temp_token_account_pubkey),
-            initializer_token_to_receive_account_pubkey: Pubkey::new_from_array(*initializer_token_to_receive_account_pubkey),
-            expected_amount: u64::from_le_bytes(*expected_amount),
-        })
+        let src = AccountType::Escrow.strip(src)?;
+        let (version, src) = src.split_first().ok_or(ProgramError::InvalidAccountData)?;
+        match *version {
+            // NOTE Version 0 is a freshly created (all zero) account that InitEscrow is
+            // about to fill in
+            0 | Escrow::VERSION => unpack_borsh(src),
+            // A LegacyEscrow has to go through MigrateEscrow first
+            _ => Err(ProgramError::InvalidAccountData),
+        }
     }
 
     // Let's SERIALIZE STATE using pack_into_slice()
@@ -105,27 +416,197 @@ impl Pack for Escrow {
     // thanks to unpack_from_slice(). We don't do this for unpack_from_slice
     // because there was no self yet!
     fn pack_into_slice(&self, dst: &mut [u8]) {
-        let dst = array_mut_ref![dst, 0, Escrow::LEN];
-        let (
-            is_initialized_dst,
-            initializer_pubkey_dst,
-            temp_token_account_pubkey_dst,
-            initializer_token_to_receive_account_pubkey_dst,
-            expected_amount_dst,
-        ) = mut_array_refs![dst, 1, 32, 32, 32, 8];
-
-        let Escrow {
-            is_initialized,
-            initializer_pubkey,
-            temp_token_account_pubkey,
-            initializer_token_to_receive_account_pubkey,
+        dst[0] = AccountType::Escrow as u8;
+        dst[1] = Escrow::VERSION;
+        pack_borsh(self, &mut dst[2..])
+    }
+}
+
+// Deserializes a state object from the start of an account's data.
+// NOTE Pack::unpack() already checked that the data is exactly LEN bytes long. Since
+// the serialized object can be shorter than LEN (e.g., when an Option is None),
+// we don't require Borsh to consume every byte.
+fn unpack_borsh<T: BorshDeserialize>(src: &[u8]) -> Result<T, ProgramError> {
+    try_from_slice_unchecked(src).map_err(|_| ProgramError::InvalidAccountData)
+}
+
+// Serializes a state object into an account's data and zeroes whatever is left, so
+// no stale bytes of the previous state stick around.
+// NOTE Pack::pack() already checked that the data is exactly LEN bytes long, and LEN
+// is the worst case size, so the object always fits.
+fn pack_borsh<T: BorshSerialize>(value: &T, dst: &mut [u8]) {
+    let mut remaining = dst;
+    value
+        .serialize(&mut remaining)
+        .expect("LEN must cover the worst case serialized size");
+    for byte in remaining.iter_mut() {
+        *byte = 0;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // A plain (non-basket) escrow offering `offered_amount` X tokens for
+    // `expected_amount` Y tokens
+    fn new_escrow(offered_amount: u64, expected_amount: u64) -> Escrow {
+        Escrow {
+            is_initialized: true,
+            initializer_pubkey: Pubkey::new_unique(),
+            temp_token_account_pubkey: Pubkey::new_unique(),
+            initializer_token_to_receive_account_pubkey: Pubkey::new_unique(),
             expected_amount,
-        } = self;
+            bump_seed: 254,
+            expires_at: Some(1_700_000_000),
+            offered_amount,
+            remaining_amount: offered_amount,
+            offered_mint: Pubkey::new_unique(),
+            requested_mint: Pubkey::new_unique(),
+            offered_kind: AssetKind::Token,
+            requested_kind: AssetKind::Token,
+            nonce: 7,
+            allowed_taker: Some(Pubkey::new_unique()),
+            taker_merkle_root: Some([3; 32]),
+            basket_offered: vec![],
+            basket_requested: vec![],
+            offered_collection: None,
+            offered_token_program: TokenProgram::SplToken,
+            requested_token_program: TokenProgram::SplToken,
+            offered_decimals: 0,
+            requested_decimals: 0,
+            basket_offered_decimals: vec![],
+            basket_requested_decimals: vec![],
+        }
+    }
+
+    // Takes the offer in the given fills (like Exchange does) and returns what was paid
+    // for each of them
+    fn take(escrow: &mut Escrow, fills: &[u64]) -> Vec<u64> {
+        fills
+            .iter()
+            .map(|fill| {
+                let payment = escrow.payment_for_fill(*fill).unwrap();
+                escrow.remaining_amount -= fill;
+                payment
+            })
+            .collect()
+    }
+
+    #[test]
+    fn fills_of_a_fully_taken_offer_add_up_to_expected_amount() {
+        for (offered_amount, expected_amount, fills) in [
+            (3, 10, vec![1, 1, 1]),
+            (3, 10, vec![2, 1]),
+            (7, 5, vec![1, 2, 3, 1]),
+            (100, 1, vec![33, 33, 34]),
+            (10, 10, vec![10]),
+        ] {
+            let mut escrow = new_escrow(offered_amount, expected_amount);
+            let paid: u64 = take(&mut escrow, &fills).iter().sum();
+            assert_eq!(paid, expected_amount);
+            assert_eq!(escrow.remaining_amount, 0);
+        }
+    }
+
+    #[test]
+    fn partial_fills_never_underpay() {
+        let (offered_amount, expected_amount) = (7u64, 5u64);
+        let mut escrow = new_escrow(offered_amount, expected_amount);
+        let mut paid = 0u64;
+        for fill in [1, 1, 2, 1, 1] {
+            paid += take(&mut escrow, &[fill])[0];
+            let filled = offered_amount - escrow.remaining_amount;
+            // paid / filled >= expected_amount / offered_amount
+            assert!(paid as u128 * offered_amount as u128 >= filled as u128 * expected_amount as u128);
+        }
+    }
+
+    #[test]
+    fn expected_amount_larger_than_offered_amount() {
+        let mut escrow = new_escrow(3, 1_000_000);
+        assert_eq!(take(&mut escrow, &[1, 1, 1]), vec![333_334, 333_333, 333_333]);
+    }
+
+    #[test]
+    fn amounts_near_u64_max() {
+        let mut escrow = new_escrow(u64::MAX, u64::MAX - 1);
+        let paid = take(&mut escrow, &[u64::MAX / 2, u64::MAX / 2, 1]);
+        assert_eq!(paid.iter().map(|payment| *payment as u128).sum::<u128>(), (u64::MAX - 1) as u128);
+
+        let mut escrow = new_escrow(2, u64::MAX);
+        assert_eq!(take(&mut escrow, &[1, 1]), vec![u64::MAX / 2 + 1, u64::MAX / 2]);
+    }
 
-        is_initialized_dst[0] = *is_initialized as u8;
-        initializer_pubkey_dst.copy_from_slice(initializer_pubkey.as_ref());
-        temp_token_account_pubkey_dst.copy_from_slice(temp_token_account_pubkey.as_ref());
-        initializer_token_to_receive_account_pubkey_dst.copy_from_slice(initializer_token_to_receive_account_pubkey.as_ref());
-        *expected_amount_dst = expected_amount.to_le_bytes();
+    #[test]
+    fn fill_larger_than_remaining_amount_is_rejected() {
+        let mut escrow = new_escrow(10, 10);
+        take(&mut escrow, &[6]);
+        assert_eq!(escrow.payment_for_fill(5), None);
+    }
+
+    #[test]
+    fn small_fills_pay_the_same_fee_as_one_fill() {
+        let config = Config {
+            is_initialized: true,
+            admin: Pubkey::new_unique(),
+            fee_bps: 30,
+            treasury: Pubkey::new_unique(),
+            paused: false,
+            bump_seed: 255,
+        };
+        let (offered_amount, expected_amount) = (1_000, 10_000);
+        let full_fee = config.fee_for(expected_amount).unwrap();
+        assert_eq!(full_fee, 30);
+
+        // Every fill pays 10, which on its own is far too little for a fee of even 1
+        let mut escrow = new_escrow(offered_amount, expected_amount);
+        let mut fees = 0;
+        while escrow.remaining_amount > 0 {
+            let paid_before = escrow.paid_amount().unwrap();
+            let payment = take(&mut escrow, &[1])[0];
+            assert_eq!(config.fee_for(payment), Some(0));
+            fees += config.fee_for_payment(paid_before, payment).unwrap();
+        }
+        assert_eq!(escrow.paid_amount(), Some(expected_amount));
+        assert_eq!(fees, full_fee);
+    }
+
+    fn leg() -> BasketLeg {
+        BasketLeg { mint: Pubkey::new_unique(), amount: 5, account: Pubkey::new_unique() }
+    }
+
+    #[test]
+    fn worst_case_len() {
+        let legs = || vec![leg(), leg(), leg()];
+        let escrow = Escrow {
+            basket_offered: legs(),
+            basket_requested: legs(),
+            offered_collection: Some(Pubkey::new_unique()),
+            basket_offered_decimals: vec![6; Escrow::MAX_BASKET_LEGS],
+            basket_requested_decimals: vec![9; Escrow::MAX_BASKET_LEGS],
+            ..new_escrow(10, 20)
+        };
+        let body = escrow.try_to_vec().unwrap();
+        assert_eq!(body.len() + 2, Escrow::LEN);
+
+        let mut data = vec![0; Escrow::LEN];
+        Escrow::pack(escrow, &mut data).unwrap();
+        assert_eq!(&data[..2], &[AccountType::Escrow as u8, Escrow::VERSION]);
+        assert_eq!(Escrow::unpack(&data).unwrap().try_to_vec().unwrap(), body);
+    }
+
+    #[test]
+    fn legacy_escrow_decodes() {
+        let legacy = LegacyEscrow {
+            is_initialized: true,
+            initializer_pubkey: Pubkey::new_unique(),
+            temp_token_account_pubkey: Pubkey::new_unique(),
+            initializer_token_to_receive_account_pubkey: Pubkey::new_unique(),
+            expected_amount: 20,
+        };
+        let data = legacy.try_to_vec().unwrap();
+        assert_eq!(data.len(), LegacyEscrow::LEN);
+        assert_eq!(LegacyEscrow::unpack(&data), Ok(legacy));
     }
 }
@@ -0,0 +1,551 @@
+// Helpers for talking to both token programs: the original SPL Token program and
+// Token-2022 (aka Token Extensions).
+// NOTE Token-2022 understands the exact same instructions as SPL Token, and its token
+// accounts and mints start with the exact same layout. Only when a mint uses extensions
+// (e.g., transfer fees) do both accounts grow: an account type byte at offset 165,
+// followed by the extensions as type-length-value (TLV) entries.
+// NOTE The spl-token-2022 crate needs a much newer solana-program than the one we're
+// on, so (just like metadata.rs) we handle the few bits we need ourselves.
+use std::convert::{TryFrom, TryInto};
+
+use solana_program::{
+    instruction::{AccountMeta, Instruction},
+    program_error::ProgramError,
+    program_pack::Pack,
+    pubkey::Pubkey,
+};
+
+use spl_token::{
+    instruction::AuthorityType,
+    state::{Account as TokenAccount, Mint, Multisig},
+};
+
+use crate::{error::EscrowError, state::TokenProgram};
+
+/// The Token-2022 program, same as spl_token::id() for the SPL Token program
+pub mod spl_token_2022 {
+    solana_program::declare_id!("TokenzQdBNbLqP5VEhdkAS6EPFLC1PHnBqCXEpPxuEb");
+}
+
+/// The Associated Token Account program, which creates every wallet's canonical token
+/// account for a mint
+pub mod spl_associated_token_account {
+    solana_program::declare_id!("ATokenGPvbdGVxr1b2hvZbsiqW5xWH25efTNsLJA8knL");
+}
+
+// The account type byte Token-2022 writes right after the base layout
+const ACCOUNT_TYPE_MINT: u8 = 1;
+const ACCOUNT_TYPE_ACCOUNT: u8 = 2;
+
+// The mint extension types (see spl_token_2022::extension::ExtensionType) escrows support
+const TRANSFER_FEE_CONFIG: u16 = 1;
+const MINT_CLOSE_AUTHORITY: u16 = 3;
+const INTEREST_BEARING_CONFIG: u16 = 10;
+const METADATA_POINTER: u16 = 18;
+const TOKEN_METADATA: u16 = 19;
+const GROUP_POINTER: u16 = 20;
+const TOKEN_GROUP: u16 = 21;
+const GROUP_MEMBER_POINTER: u16 = 22;
+const TOKEN_GROUP_MEMBER: u16 = 23;
+// ...and the one holding the transfer fees withheld in a token account
+const TRANSFER_FEE_AMOUNT: u16 = 2;
+
+// NOTE Of the supported extensions, only TransferFeeConfig needs an extension on every
+// token account of the mint (see account_len). We reject every other extension, since
+// some (e.g., NonTransferable, TransferHook, a frozen DefaultAccountState or a
+// PermanentDelegate) could keep a trade from ever settling or drain the vault, and
+// others (e.g., ConfidentialTransferFeeConfig) need account extensions we don't create.
+const SUPPORTED_MINT_EXTENSIONS: [u16; 9] = [
+    TRANSFER_FEE_CONFIG,
+    MINT_CLOSE_AUTHORITY,
+    INTEREST_BEARING_CONFIG,
+    METADATA_POINTER,
+    TOKEN_METADATA,
+    GROUP_POINTER,
+    TOKEN_GROUP,
+    GROUP_MEMBER_POINTER,
+    TOKEN_GROUP_MEMBER,
+];
+
+// Token-2022's TransferFeeExtension instruction and its HarvestWithheldTokensToMint
+// sub-instruction (see spl_token_2022::extension::transfer_fee::instruction)
+const TRANSFER_FEE_EXTENSION: u8 = 26;
+const HARVEST_WITHHELD_TOKENS_TO_MINT: u8 = 4;
+
+// The Associated Token Account program's CreateIdempotent instruction, which (unlike
+// Create) succeeds when the account already exists
+const CREATE_IDEMPOTENT: u8 = 1;
+
+// Transfer fees are expressed in basis points of the transferred amount
+const ONE_IN_BASIS_POINTS: u128 = 10_000;
+
+// Instruction builders mirroring spl_token::instruction.
+// NOTE spl_token's builders refuse any program id but their own, so we build the
+// instruction for SPL Token and then send it to whichever token program we need.
+
+/// Creates an `InitializeAccount2` instruction for the given token program
+pub fn initialize_account2(
+    token_program_id: &Pubkey,
+    account: &Pubkey,
+    mint: &Pubkey,
+    owner: &Pubkey,
+) -> Result<Instruction, ProgramError> {
+    for_program(
+        spl_token::instruction::initialize_account2(&spl_token::id(), account, mint, owner),
+        token_program_id,
+    )
+}
+
+/// Creates a `TransferChecked` instruction for the given token program.
+/// NOTE We never send a plain `Transfer`: Token-2022 refuses one for tokens whose mint
+/// charges a transfer fee, and only `TransferChecked` checks the decimals we expect.
+#[allow(clippy::too_many_arguments)]
+pub fn transfer_checked(
+    token_program_id: &Pubkey,
+    source: &Pubkey,
+    mint: &Pubkey,
+    destination: &Pubkey,
+    authority: &Pubkey,
+    signer_pubkeys: &[&Pubkey],
+    amount: u64,
+    decimals: u8,
+) -> Result<Instruction, ProgramError> {
+    for_program(
+        spl_token::instruction::transfer_checked(
+            &spl_token::id(),
+            source,
+            mint,
+            destination,
+            authority,
+            signer_pubkeys,
+            amount,
+            decimals,
+        ),
+        token_program_id,
+    )
+}
+
+/// Creates a `CloseAccount` instruction for the given token program
+pub fn close_account(
+    token_program_id: &Pubkey,
+    account: &Pubkey,
+    destination: &Pubkey,
+    owner: &Pubkey,
+    signer_pubkeys: &[&Pubkey],
+) -> Result<Instruction, ProgramError> {
+    for_program(
+        spl_token::instruction::close_account(&spl_token::id(), account, destination, owner, signer_pubkeys),
+        token_program_id,
+    )
+}
+
+/// Creates a `SetAuthority` instruction for the given token program
+pub fn set_authority(
+    token_program_id: &Pubkey,
+    owned: &Pubkey,
+    new_authority: Option<&Pubkey>,
+    authority_type: AuthorityType,
+    owner: &Pubkey,
+    signer_pubkeys: &[&Pubkey],
+) -> Result<Instruction, ProgramError> {
+    for_program(
+        spl_token::instruction::set_authority(&spl_token::id(), owned, new_authority, authority_type, owner, signer_pubkeys),
+        token_program_id,
+    )
+}
+
+/// Creates a Token-2022 `HarvestWithheldTokensToMint` instruction, which moves the transfer
+/// fees withheld in the given token accounts into the mint. Anybody may call it.
+/// NOTE Token-2022 won't close a token account that still holds withheld fees.
+pub fn harvest_withheld_tokens_to_mint(
+    token_program_id: &Pubkey,
+    mint: &Pubkey,
+    sources: &[&Pubkey],
+) -> Result<Instruction, ProgramError> {
+    if *token_program_id != spl_token_2022::id() {
+        return Err(ProgramError::IncorrectProgramId);
+    }
+    let mut accounts = vec![AccountMeta::new(*mint, false)];
+    accounts.extend(sources.iter().map(|source| AccountMeta::new(**source, false)));
+    Ok(Instruction {
+        program_id: *token_program_id,
+        accounts,
+        data: vec![TRANSFER_FEE_EXTENSION, HARVEST_WITHHELD_TOKENS_TO_MINT],
+    })
+}
+
+/// Finds the associated token account of `wallet` for `mint`
+/// NOTE It's a PDA of the ATA program, seeded with the token program as well, so a
+/// wallet has a different one for each token program.
+pub fn get_associated_token_address(wallet: &Pubkey, mint: &Pubkey, token_program_id: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(
+        &[wallet.as_ref(), token_program_id.as_ref(), mint.as_ref()],
+        &spl_associated_token_account::id(),
+    )
+    .0
+}
+
+/// Creates an Associated Token Account program `CreateIdempotent` instruction, which creates
+/// the associated token account of `wallet` for `mint` unless it already exists
+/// NOTE The ATA program makes sure an existing account belongs to the wallet and holds the mint.
+pub fn create_associated_token_account_idempotent(
+    payer: &Pubkey,
+    wallet: &Pubkey,
+    mint: &Pubkey,
+    token_program_id: &Pubkey,
+) -> Instruction {
+    Instruction {
+        program_id: spl_associated_token_account::id(),
+        accounts: vec![
+            AccountMeta::new(*payer, true),
+            AccountMeta::new(get_associated_token_address(wallet, mint, token_program_id), false),
+            AccountMeta::new_readonly(*wallet, false),
+            AccountMeta::new_readonly(*mint, false),
+            AccountMeta::new_readonly(solana_program::system_program::id(), false),
+            AccountMeta::new_readonly(*token_program_id, false),
+        ],
+        data: vec![CREATE_IDEMPOTENT],
+    }
+}
+
+fn for_program(
+    instruction: Result<Instruction, ProgramError>,
+    token_program_id: &Pubkey,
+) -> Result<Instruction, ProgramError> {
+    if TokenProgram::from_id(token_program_id).is_none() {
+        return Err(ProgramError::IncorrectProgramId);
+    }
+    let mut instruction = instruction?;
+    instruction.program_id = *token_program_id;
+    Ok(instruction)
+}
+
+/// Unpacks the base layout of a token account of either token program
+pub fn unpack_account(data: &[u8]) -> Result<TokenAccount, ProgramError> {
+    check_account_type(data, TokenAccount::LEN, ACCOUNT_TYPE_ACCOUNT)?;
+    TokenAccount::unpack(&data[..TokenAccount::LEN])
+}
+
+/// Unpacks the base layout of a mint of either token program
+pub fn unpack_mint(data: &[u8]) -> Result<Mint, ProgramError> {
+    check_account_type(data, Mint::LEN, ACCOUNT_TYPE_MINT)?;
+    Mint::unpack(&data[..Mint::LEN])
+}
+
+/// The fee a Token-2022 mint charges on transfers during one epoch range
+/// (see spl_token_2022::extension::transfer_fee::TransferFee)
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct TransferFee {
+    /// First epoch the fee applies to
+    pub epoch: u64,
+    /// The most a single transfer can be charged
+    pub maximum_fee: u64,
+    /// The fee in basis points (1/100th of a percent) of the transferred amount
+    pub transfer_fee_basis_points: u16,
+}
+
+impl TransferFee {
+    /// The fee charged when transferring `pre_fee_amount` tokens
+    pub fn calculate_fee(&self, pre_fee_amount: u64) -> Option<u64> {
+        let transfer_fee_basis_points = self.transfer_fee_basis_points as u128;
+        if transfer_fee_basis_points == 0 || pre_fee_amount == 0 {
+            return Some(0);
+        }
+        // NOTE The fee is rounded UP, same as Token-2022 does
+        let numerator = (pre_fee_amount as u128).checked_mul(transfer_fee_basis_points)?;
+        let raw_fee = numerator
+            .checked_add(ONE_IN_BASIS_POINTS)?
+            .checked_sub(1)?
+            .checked_div(ONE_IN_BASIS_POINTS)?;
+        let fee = u64::try_from(raw_fee).ok()?;
+        Some(fee.min(self.maximum_fee))
+    }
+
+    /// How many tokens have to be transferred so that `post_fee_amount` tokens arrive
+    pub fn calculate_pre_fee_amount(&self, post_fee_amount: u64) -> Option<u64> {
+        let transfer_fee_basis_points = self.transfer_fee_basis_points as u128;
+        match (transfer_fee_basis_points, post_fee_amount) {
+            (0, _) => Some(post_fee_amount),
+            (_, 0) => Some(0),
+            (ONE_IN_BASIS_POINTS, _) => post_fee_amount.checked_add(self.maximum_fee),
+            _ => {
+                let numerator = (post_fee_amount as u128).checked_mul(ONE_IN_BASIS_POINTS)?;
+                let denominator = ONE_IN_BASIS_POINTS.checked_sub(transfer_fee_basis_points)?;
+                let raw_pre_fee_amount = numerator
+                    .checked_add(denominator)?
+                    .checked_sub(1)?
+                    .checked_div(denominator)?;
+                // Once the fee hits its maximum, the maximum is all that's added on top
+                if raw_pre_fee_amount.checked_sub(post_fee_amount as u128)? >= self.maximum_fee as u128 {
+                    post_fee_amount.checked_add(self.maximum_fee)
+                } else {
+                    u64::try_from(raw_pre_fee_amount).ok()
+                }
+            }
+        }
+    }
+}
+
+/// The transfer fee a mint charges during the given epoch. Mints without the
+/// TransferFeeConfig extension (including every SPL Token mint) charge nothing.
+pub fn transfer_fee(mint_data: &[u8], epoch: u64) -> Result<TransferFee, ProgramError> {
+    // NOTE TransferFeeConfig holds two authorities (2 * 32), the fees withheld in the
+    // mint (8) and then the older and newer TransferFee (2 * 18). The newer one kicks
+    // in at its epoch, so fee changes can't surprise anybody mid-epoch.
+    let config = match extensions(mint_data, Mint::LEN, ACCOUNT_TYPE_MINT)?
+        .into_iter()
+        .find(|(extension_type, _)| *extension_type == TRANSFER_FEE_CONFIG)
+    {
+        Some((_, config)) if config.len() == 108 => config,
+        Some(_) => return Err(ProgramError::InvalidAccountData),
+        None => return Ok(TransferFee::default()),
+    };
+    let older_transfer_fee = unpack_transfer_fee(&config[72..90]);
+    let newer_transfer_fee = unpack_transfer_fee(&config[90..108]);
+    Ok(if epoch >= newer_transfer_fee.epoch { newer_transfer_fee } else { older_transfer_fee })
+}
+
+/// The transfer fees withheld in a token account, which have to be harvested before
+/// Token-2022 lets anybody close it
+pub fn withheld_amount(account_data: &[u8]) -> Result<u64, ProgramError> {
+    Ok(extensions(account_data, TokenAccount::LEN, ACCOUNT_TYPE_ACCOUNT)?
+        .into_iter()
+        .find(|(extension_type, _)| *extension_type == TRANSFER_FEE_AMOUNT)
+        .and_then(|(_, amount)| amount.try_into().ok())
+        .map(u64::from_le_bytes)
+        .unwrap_or_default())
+}
+
+/// Checks that a mint only uses extensions an escrow can settle (none for SPL Token)
+pub fn assert_supported_extensions(mint_data: &[u8]) -> Result<(), ProgramError> {
+    for (extension_type, _) in extensions(mint_data, Mint::LEN, ACCOUNT_TYPE_MINT)? {
+        if !SUPPORTED_MINT_EXTENSIONS.contains(&extension_type) {
+            return Err(EscrowError::UnsupportedMintExtension.into());
+        }
+    }
+    Ok(())
+}
+
+/// The size of a token account for the given mint, including the extensions
+/// Token-2022 requires for it (none for SPL Token).
+pub fn account_len(token_program_id: &Pubkey, mint_data: &[u8]) -> Result<usize, ProgramError> {
+    if *token_program_id != spl_token_2022::id() {
+        return Ok(TokenAccount::LEN);
+    }
+
+    // Every extension takes a 2 byte type and a 2 byte length in front of its value
+    let mut extensions_len = 0;
+    for (extension_type, _) in extensions(mint_data, Mint::LEN, ACCOUNT_TYPE_MINT)? {
+        extensions_len += match extension_type {
+            TRANSFER_FEE_CONFIG => 4 + 8, // TransferFeeAmount { withheld_amount: u64 }
+            extension_type if SUPPORTED_MINT_EXTENSIONS.contains(&extension_type) => 0,
+            _ => return Err(EscrowError::UnsupportedMintExtension.into()),
+        };
+    }
+    if extensions_len == 0 {
+        return Ok(TokenAccount::LEN);
+    }
+    // NOTE Token-2022 never makes a token account exactly as long as a multisig
+    // account (it adds 2 bytes of padding instead), so it can tell them apart.
+    let len = TokenAccount::LEN + 1 + extensions_len;
+    Ok(if len == Multisig::LEN { len + 2 } else { len })
+}
+
+// Returns the (type, value) of every extension of a Token-2022 mint or token account
+fn extensions(data: &[u8], base_len: usize, account_type: u8) -> Result<Vec<(u16, &[u8])>, ProgramError> {
+    check_account_type(data, base_len, account_type)?;
+    let mut extensions = Vec::new();
+    if data.len() <= base_len {
+        return Ok(extensions);
+    }
+
+    // NOTE The extensions start after the account type byte, which comes right after
+    // the base layout (a mint is padded to the size of a token account first).
+    let mut offset = TokenAccount::LEN + 1;
+    while offset + 4 <= data.len() {
+        let extension_type = u16::from_le_bytes([data[offset], data[offset + 1]]);
+        let length = u16::from_le_bytes([data[offset + 2], data[offset + 3]]) as usize;
+        // The rest of the account is unused (zero) space
+        if extension_type == 0 {
+            break;
+        }
+        let value = data
+            .get(offset + 4..offset + 4 + length)
+            .ok_or(ProgramError::InvalidAccountData)?;
+        extensions.push((extension_type, value));
+        offset += 4 + length;
+    }
+    Ok(extensions)
+}
+
+fn unpack_transfer_fee(data: &[u8]) -> TransferFee {
+    let u64_at = |offset: usize| {
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(&data[offset..offset + 8]);
+        u64::from_le_bytes(bytes)
+    };
+    TransferFee {
+        epoch: u64_at(0),
+        maximum_fee: u64_at(8),
+        transfer_fee_basis_points: u16::from_le_bytes([data[16], data[17]]),
+    }
+}
+
+// Makes sure an account with extensions really is of the expected kind, since both
+// Token-2022 mints and token accounts grow past TokenAccount::LEN bytes
+fn check_account_type(data: &[u8], base_len: usize, account_type: u8) -> Result<(), ProgramError> {
+    if data.len() < base_len || data.len() == Multisig::LEN {
+        return Err(ProgramError::InvalidAccountData);
+    }
+    if data.len() > base_len && data.get(TokenAccount::LEN) != Some(&account_type) {
+        return Err(ProgramError::InvalidAccountData);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // The data of an (initialized) mint with the given (type, value) extensions, laid out
+    // the way Token-2022 does it. Without extensions, it's a plain SPL Token mint.
+    fn mint_data(extensions: &[(u16, Vec<u8>)]) -> Vec<u8> {
+        let mint = Mint {
+            supply: 1_000_000,
+            decimals: 6,
+            is_initialized: true,
+            ..Mint::default()
+        };
+        let mut data = vec![0; Mint::LEN];
+        mint.pack_into_slice(&mut data);
+        if extensions.is_empty() {
+            return data;
+        }
+        data.resize(TokenAccount::LEN, 0);
+        data.push(ACCOUNT_TYPE_MINT);
+        for (extension_type, value) in extensions {
+            data.extend_from_slice(&extension_type.to_le_bytes());
+            data.extend_from_slice(&(value.len() as u16).to_le_bytes());
+            data.extend_from_slice(value);
+        }
+        data
+    }
+
+    #[test]
+    fn account_len_of_supported_mints() {
+        let spl_token_mint = mint_data(&[]);
+        assert_eq!(account_len(&spl_token::id(), &spl_token_mint), Ok(TokenAccount::LEN));
+        assert_eq!(account_len(&spl_token_2022::id(), &spl_token_mint), Ok(TokenAccount::LEN));
+
+        let transfer_fee_mint = mint_data(&[(TRANSFER_FEE_CONFIG, vec![0; 108])]);
+        assert_eq!(assert_supported_extensions(&transfer_fee_mint), Ok(()));
+        assert_eq!(account_len(&spl_token_2022::id(), &transfer_fee_mint), Ok(TokenAccount::LEN + 1 + 4 + 8));
+
+        // Extensions that only live on the mint don't grow the token accounts
+        let metadata_mint = mint_data(&[(METADATA_POINTER, vec![0; 64]), (MINT_CLOSE_AUTHORITY, vec![0; 32])]);
+        assert_eq!(assert_supported_extensions(&metadata_mint), Ok(()));
+        assert_eq!(account_len(&spl_token_2022::id(), &metadata_mint), Ok(TokenAccount::LEN));
+    }
+
+    #[test]
+    fn unsettleable_extensions_are_rejected() {
+        let unsupported_mint_extension: ProgramError = EscrowError::UnsupportedMintExtension.into();
+        // NonTransferable, PermanentDelegate, TransferHook and ConfidentialTransferFeeConfig
+        for (extension_type, len) in [(9, 0), (12, 32), (14, 64), (16, 129)] {
+            let mint = mint_data(&[(TRANSFER_FEE_CONFIG, vec![0; 108]), (extension_type, vec![0; len])]);
+            assert_eq!(assert_supported_extensions(&mint), Err(unsupported_mint_extension.clone()));
+            assert_eq!(account_len(&spl_token_2022::id(), &mint), Err(unsupported_mint_extension.clone()));
+        }
+    }
+
+    // The value of a TransferFeeConfig extension: two authorities, the fees withheld in
+    // the mint and the older and newer TransferFee (epoch, maximum_fee, basis points)
+    fn transfer_fee_config(older: TransferFee, newer: TransferFee) -> Vec<u8> {
+        let mut config = vec![7; 64];
+        config.extend_from_slice(&42u64.to_le_bytes());
+        for transfer_fee in [older, newer] {
+            config.extend_from_slice(&transfer_fee.epoch.to_le_bytes());
+            config.extend_from_slice(&transfer_fee.maximum_fee.to_le_bytes());
+            config.extend_from_slice(&transfer_fee.transfer_fee_basis_points.to_le_bytes());
+        }
+        config
+    }
+
+    fn fee(transfer_fee_basis_points: u16, maximum_fee: u64) -> TransferFee {
+        TransferFee { epoch: 0, maximum_fee, transfer_fee_basis_points }
+    }
+
+    // Whatever calculate_pre_fee_amount says has to be sent, `post_fee_amount` arrives
+    fn assert_round_trip(transfer_fee: TransferFee, post_fee_amount: u64) {
+        let pre_fee_amount = transfer_fee.calculate_pre_fee_amount(post_fee_amount).unwrap();
+        let fee = transfer_fee.calculate_fee(pre_fee_amount).unwrap();
+        assert_eq!(pre_fee_amount - fee, post_fee_amount, "{:?} {}", transfer_fee, post_fee_amount);
+    }
+
+    #[test]
+    fn zero_basis_points() {
+        let transfer_fee = fee(0, u64::MAX);
+        assert_eq!(transfer_fee.calculate_fee(u64::MAX), Some(0));
+        assert_eq!(transfer_fee.calculate_pre_fee_amount(1_000), Some(1_000));
+        assert_round_trip(transfer_fee, u64::MAX);
+    }
+
+    #[test]
+    fn one_basis_point() {
+        // The fee is rounded up, so even a single token pays one
+        let transfer_fee = fee(1, u64::MAX);
+        assert_eq!(transfer_fee.calculate_fee(1), Some(1));
+        assert_eq!(transfer_fee.calculate_fee(10_000), Some(1));
+        assert_eq!(transfer_fee.calculate_fee(10_001), Some(2));
+        assert_eq!(transfer_fee.calculate_pre_fee_amount(1), Some(2));
+        assert_eq!(transfer_fee.calculate_pre_fee_amount(9_999), Some(10_000));
+        for post_fee_amount in [0, 1, 2, 9_998, 9_999, 10_000, 1_000_000, u64::MAX / 2] {
+            assert_round_trip(transfer_fee, post_fee_amount);
+        }
+    }
+
+    #[test]
+    fn ten_thousand_basis_points() {
+        // Everything up to the maximum fee is taken
+        let transfer_fee = fee(10_000, 5_000);
+        assert_eq!(transfer_fee.calculate_fee(1), Some(1));
+        assert_eq!(transfer_fee.calculate_fee(5_000), Some(5_000));
+        assert_eq!(transfer_fee.calculate_fee(5_001), Some(5_000));
+        assert_eq!(transfer_fee.calculate_pre_fee_amount(0), Some(0));
+        assert_eq!(transfer_fee.calculate_pre_fee_amount(1), Some(5_001));
+        assert_eq!(transfer_fee.calculate_pre_fee_amount(u64::MAX), None);
+        for post_fee_amount in [1, 5_000, 1_000_000] {
+            assert_round_trip(transfer_fee, post_fee_amount);
+        }
+    }
+
+    #[test]
+    fn maximum_fee_caps_the_fee() {
+        // 1% of the amount, but never more than 50 tokens
+        let transfer_fee = fee(100, 50);
+        assert_eq!(transfer_fee.calculate_fee(1_000), Some(10));
+        assert_eq!(transfer_fee.calculate_fee(5_000), Some(50));
+        assert_eq!(transfer_fee.calculate_fee(1_000_000), Some(50));
+        assert_eq!(transfer_fee.calculate_pre_fee_amount(990), Some(1_000));
+        assert_eq!(transfer_fee.calculate_pre_fee_amount(100_000), Some(100_050));
+        for post_fee_amount in [1, 99, 990, 4_949, 4_950, 4_951, 100_000] {
+            assert_round_trip(transfer_fee, post_fee_amount);
+        }
+    }
+
+    #[test]
+    fn transfer_fee_switches_at_the_newer_epoch() {
+        let older = TransferFee { epoch: 0, maximum_fee: 10, transfer_fee_basis_points: 50 };
+        let newer = TransferFee { epoch: 5, maximum_fee: 20, transfer_fee_basis_points: 100 };
+        let mint = mint_data(&[(TRANSFER_FEE_CONFIG, transfer_fee_config(older, newer))]);
+        assert_eq!(transfer_fee(&mint, 0), Ok(older));
+        assert_eq!(transfer_fee(&mint, 4), Ok(older));
+        assert_eq!(transfer_fee(&mint, 5), Ok(newer));
+        assert_eq!(transfer_fee(&mint, 6), Ok(newer));
+
+        // Mints without the extension don't charge anything
+        assert_eq!(transfer_fee(&mint_data(&[]), 5), Ok(TransferFee::default()));
+        let metadata_mint = mint_data(&[(METADATA_POINTER, vec![0; 64])]);
+        assert_eq!(transfer_fee(&metadata_mint, 5), Ok(TransferFee::default()));
+    }
+}
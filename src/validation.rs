@@ -0,0 +1,135 @@
+// Account validation shared by every instruction in processor.rs.
+// NOTE Any account can be passed into the entrypoint, including different ones than
+// those defined in the API (inside instruction.rs). Therefore, before the processor
+// touches an account, it checks who owns it, whether it's signed/writable and whether
+// its address is the one we expect. Each check returns its own EscrowError, so a
+// failed transaction tells you exactly what was wrong with which account.
+use solana_program::{
+    account_info::AccountInfo,
+    bpf_loader_upgradeable,
+    entrypoint::ProgramResult,
+    program_error::ProgramError,
+    program_pack::Pack,
+    pubkey::Pubkey,
+};
+
+use spl_token::state::{Account as TokenAccount, Mint};
+
+use crate::{
+    error::EscrowError,
+    state::{Config, Escrow, LegacyEscrow, TokenProgram},
+    token,
+};
+
+/// Checks that the account signed the transaction
+pub fn assert_signer(account: &AccountInfo) -> ProgramResult {
+    if !account.is_signer {
+        return Err(EscrowError::AccountNotSigner.into());
+    }
+    Ok(())
+}
+
+/// Checks that the account was passed in as writable
+pub fn assert_writable(account: &AccountInfo) -> ProgramResult {
+    if !account.is_writable {
+        return Err(EscrowError::AccountNotWritable.into());
+    }
+    Ok(())
+}
+
+/// Checks that the account is owned by the given program
+pub fn assert_owned_by(account: &AccountInfo, owner: &Pubkey) -> ProgramResult {
+    if account.owner != owner {
+        return Err(EscrowError::InvalidAccountOwner.into());
+    }
+    Ok(())
+}
+
+/// Checks that the account lives at the expected address, returning `error` otherwise
+pub fn assert_address(account: &AccountInfo, expected: &Pubkey, error: EscrowError) -> ProgramResult {
+    if account.key != expected {
+        return Err(error.into());
+    }
+    Ok(())
+}
+
+/// Checks that the account is the given (executable) program we're about to call
+pub fn assert_program(account: &AccountInfo, program_id: &Pubkey) -> ProgramResult {
+    assert_address(account, program_id, EscrowError::InvalidProgramAccount)
+}
+
+/// Checks that the account is one of the token programs (SPL Token or Token-2022)
+pub fn assert_token_program(account: &AccountInfo) -> Result<TokenProgram, ProgramError> {
+    TokenProgram::from_id(account.key).ok_or_else(|| EscrowError::InvalidProgramAccount.into())
+}
+
+/// Checks that the account is owned by the given token program and unpacks it as a token account
+pub fn unpack_token_account(account: &AccountInfo, token_program_id: &Pubkey) -> Result<TokenAccount, ProgramError> {
+    assert_owned_by(account, token_program_id)?;
+    token::unpack_account(&account.data.borrow())
+}
+
+/// Checks that the account is owned by the given token program and unpacks it as a mint
+pub fn unpack_mint(account: &AccountInfo, token_program_id: &Pubkey) -> Result<Mint, ProgramError> {
+    assert_owned_by(account, token_program_id)?;
+    token::unpack_mint(&account.data.borrow())
+}
+
+/// Like unpack_mint, but also checks that the mint only uses Token-2022 extensions an
+/// escrow can settle. NOTE Only InitEscrow needs this: once a trade is open, its mints are set.
+pub fn unpack_supported_mint(account: &AccountInfo, token_program_id: &Pubkey) -> Result<Mint, ProgramError> {
+    let mint = unpack_mint(account, token_program_id)?;
+    token::assert_supported_extensions(&account.data.borrow())?;
+    Ok(mint)
+}
+
+/// Checks that `authority` is this program's upgrade authority, as recorded in the
+/// program's ProgramData account (the upgradeable BPF loader's PDA `[program_id]`).
+/// NOTE The loader writes its accounts with bincode, which we decode by hand: a u32 tag
+/// (3 = ProgramData), the u64 slot of the last deploy and then an Option<Pubkey> (a 1
+/// byte tag followed by the key). An immutable program has no upgrade authority.
+pub fn assert_upgrade_authority(
+    program_data_account: &AccountInfo,
+    authority: &AccountInfo,
+    program_id: &Pubkey,
+) -> ProgramResult {
+    assert_owned_by(program_data_account, &bpf_loader_upgradeable::id())?;
+    let (program_data_address, _) = Pubkey::find_program_address(&[program_id.as_ref()], &bpf_loader_upgradeable::id());
+    assert_address(program_data_account, &program_data_address, EscrowError::InvalidProgramDataAccount)?;
+
+    let data = program_data_account.data.borrow();
+    if data.len() < PROGRAM_DATA_AUTHORITY_OFFSET + 32 || data[..4] != PROGRAM_DATA_TAG.to_le_bytes() {
+        return Err(EscrowError::InvalidProgramDataAccount.into());
+    }
+    let has_authority = data[PROGRAM_DATA_AUTHORITY_OFFSET - 1] == 1;
+    if !has_authority || data[PROGRAM_DATA_AUTHORITY_OFFSET..PROGRAM_DATA_AUTHORITY_OFFSET + 32] != authority.key.to_bytes() {
+        return Err(EscrowError::NotUpgradeAuthority.into());
+    }
+    assert_signer(authority)
+}
+
+// UpgradeableLoaderState::ProgramData's tag, and where its upgrade authority's key starts:
+// 4 (u32 tag) + 8 (u64 slot) + 1 (Option tag) = 13
+const PROGRAM_DATA_TAG: u32 = 3;
+const PROGRAM_DATA_AUTHORITY_OFFSET: usize = 13;
+
+/// Checks that the escrow account is owned by this program and writable, and unpacks it.
+/// Escrows stored in an older layout have to go through `MigrateEscrow` first.
+pub fn load_escrow(escrow_account: &AccountInfo, program_id: &Pubkey) -> Result<Escrow, ProgramError> {
+    assert_owned_by(escrow_account, program_id)?;
+    assert_writable(escrow_account)?;
+    if escrow_account.data_len() == LegacyEscrow::LEN {
+        return Err(EscrowError::EscrowNeedsMigration.into());
+    }
+    Escrow::unpack(&escrow_account.data.borrow())
+}
+
+/// Checks that the config account is the program's config PDA and unpacks it
+pub fn load_config(config_account: &AccountInfo, program_id: &Pubkey) -> Result<Config, ProgramError> {
+    assert_owned_by(config_account, program_id)?;
+    let config = Config::unpack(&config_account.data.borrow())?;
+    let config_address = Pubkey::create_program_address(&[&b"config"[..], &[config.bump_seed]], program_id)
+        .map_err(|_| EscrowError::InvalidConfigAddress)?;
+    assert_address(config_account, &config_address, EscrowError::InvalidConfigAddress)?;
+    Ok(config)
+}
@@ -0,0 +1,483 @@
+// A small stand-in for solana-program-test, which doesn't build on current toolchains.
+// NOTE Just like program-test, we run the program natively: every instruction's accounts
+// are serialized into the same input buffer the BPF loader hands a deployed program (see
+// solana_program::entrypoint::deserialize), and the syscalls (CPIs and sysvars) are routed
+// through solana_program's program_stubs. Only the SPL Token program and the parts of the
+// system program our program calls are available to CPI into.
+// NOTE Every test binary only uses some of the helpers
+#![allow(dead_code)]
+
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    convert::TryInto,
+    mem::size_of,
+    sync::Once,
+};
+
+use solana_program::{
+    account_info::AccountInfo,
+    bpf_loader, bpf_loader_upgradeable,
+    clock::Clock,
+    entrypoint::{self, ProgramResult, MAX_PERMITTED_DATA_INCREASE},
+    instruction::Instruction,
+    program_error::ProgramError,
+    program_option::COption,
+    program_pack::{IsInitialized, Pack},
+    program_stubs::{self, SyscallStubs},
+    pubkey::Pubkey,
+    rent::Rent,
+    system_instruction::SystemInstruction,
+    system_program, sysvar,
+};
+use spl_token::state::{Account as TokenAccount, AccountState, Mint};
+
+use solana_escrow::{
+    instruction,
+    processor::Processor,
+    state::{Config, Escrow},
+};
+
+/// An account as stored by the bank, in between transactions
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Account {
+    pub lamports: u64,
+    pub data: Vec<u8>,
+    pub owner: Pubkey,
+    pub executable: bool,
+}
+
+impl Account {
+    pub fn new(lamports: u64, data: Vec<u8>, owner: &Pubkey) -> Self {
+        Self { lamports, data, owner: *owner, executable: false }
+    }
+}
+
+/// Holds the accounts and runs transactions against them, one instruction at a time
+pub struct Bank {
+    pub program_id: Pubkey,
+    accounts: HashMap<Pubkey, Account>,
+}
+
+impl Bank {
+    pub fn new() -> Self {
+        static STUBS: Once = Once::new();
+        STUBS.call_once(|| {
+            program_stubs::set_syscall_stubs(Box::new(Stubs));
+        });
+        CLOCK.with(|clock| *clock.borrow_mut() = Clock::default());
+        let program_id = Pubkey::new_unique();
+        PROGRAM_ID.with(|escrow_program_id| *escrow_program_id.borrow_mut() = program_id);
+
+        let mut bank = Self { program_id, accounts: HashMap::new() };
+        bank.set_program(&program_id, &bpf_loader_upgradeable::id());
+        bank.set_program(&spl_token::id(), &bpf_loader::id());
+        bank.set_program(&system_program::id(), &bpf_loader::id());
+        bank.set_account(
+            &sysvar::rent::id(),
+            Account::new(1, bincode::serialize(&Rent::default()).unwrap(), &sysvar::id()),
+        );
+        bank
+    }
+
+    pub fn rent(&self) -> Rent {
+        Rent::default()
+    }
+
+    /// Moves the Clock sysvar to the given unix timestamp
+    pub fn set_unix_timestamp(&mut self, unix_timestamp: i64) {
+        CLOCK.with(|clock| clock.borrow_mut().unix_timestamp = unix_timestamp);
+    }
+
+    pub fn set_account(&mut self, key: &Pubkey, account: Account) {
+        self.accounts.insert(*key, account);
+    }
+
+    fn set_program(&mut self, program_id: &Pubkey, loader: &Pubkey) {
+        let account = Account { executable: true, ..Account::new(1, vec![], loader) };
+        self.set_account(program_id, account);
+    }
+
+    pub fn account(&self, key: &Pubkey) -> Option<&Account> {
+        self.accounts.get(key)
+    }
+
+    pub fn lamports(&self, key: &Pubkey) -> u64 {
+        self.account(key).map_or(0, |account| account.lamports)
+    }
+
+    pub fn unpack<T: Pack + IsInitialized>(&self, key: &Pubkey) -> T {
+        T::unpack(&self.accounts[key].data).unwrap()
+    }
+
+    pub fn token_balance(&self, key: &Pubkey) -> u64 {
+        self.unpack::<TokenAccount>(key).amount
+    }
+
+    /// Creates a wallet, i.e., an account owned by the system program
+    pub fn create_wallet(&mut self, lamports: u64) -> Pubkey {
+        let key = Pubkey::new_unique();
+        self.set_account(&key, Account::new(lamports, vec![], &system_program::id()));
+        key
+    }
+
+    pub fn create_mint(&mut self, decimals: u8) -> Pubkey {
+        let mint = Mint {
+            mint_authority: COption::Some(Pubkey::new_unique()),
+            supply: u64::MAX,
+            decimals,
+            is_initialized: true,
+            freeze_authority: COption::None,
+        };
+        let key = Pubkey::new_unique();
+        self.set_packed(&key, mint, &spl_token::id());
+        key
+    }
+
+    pub fn create_token_account(&mut self, mint: &Pubkey, owner: &Pubkey, amount: u64) -> Pubkey {
+        let key = Pubkey::new_unique();
+        self.set_token_account(&key, mint, owner, amount);
+        key
+    }
+
+    pub fn set_token_account(&mut self, key: &Pubkey, mint: &Pubkey, owner: &Pubkey, amount: u64) {
+        let account = TokenAccount {
+            mint: *mint,
+            owner: *owner,
+            amount,
+            state: AccountState::Initialized,
+            ..TokenAccount::default()
+        };
+        self.set_packed(key, account, &spl_token::id());
+    }
+
+    /// Stores a rent exempt account holding the packed `state`
+    pub fn set_packed<T: Pack>(&mut self, key: &Pubkey, state: T, owner: &Pubkey) {
+        let mut data = vec![0; T::LEN];
+        T::pack(state, &mut data).unwrap();
+        self.set_account(key, Account::new(self.rent().minimum_balance(T::LEN), data, owner));
+    }
+
+    /// Creates the program's config through InitConfig, the way its upgrade authority would
+    pub fn init_config(&mut self, fee_bps: u16, treasury: &Pubkey) -> Pubkey {
+        let admin = self.create_wallet(1_000_000_000);
+        // The upgradeable loader's ProgramData account: the u32 tag (3), the u64 slot and
+        // then the Option<Pubkey> of the upgrade authority
+        let mut program_data = 3u32.to_le_bytes().to_vec();
+        program_data.extend_from_slice(&0u64.to_le_bytes());
+        program_data.push(1);
+        program_data.extend_from_slice(admin.as_ref());
+        let (program_data_address, _) = instruction::find_program_data_address(&self.program_id);
+        self.set_account(&program_data_address, Account::new(1, program_data, &bpf_loader_upgradeable::id()));
+
+        let init_config_ix = instruction::init_config(&self.program_id, &admin, fee_bps, treasury).unwrap();
+        self.process_transaction(&[init_config_ix], &[&admin]).unwrap();
+        let (config, _) = instruction::find_config_address(&self.program_id);
+        assert_eq!(self.unpack::<Config>(&config).fee_bps, fee_bps);
+        admin
+    }
+
+    pub fn escrow(&self, key: &Pubkey) -> Escrow {
+        self.unpack(key)
+    }
+
+    /// Runs the instructions one after the other. Should any of them fail, none of their
+    /// changes are kept, just like on chain.
+    pub fn process_transaction(&mut self, instructions: &[Instruction], signers: &[&Pubkey]) -> ProgramResult {
+        let mut accounts = self.accounts.clone();
+        for instruction in instructions {
+            for meta in &instruction.accounts {
+                if meta.is_signer && !signers.contains(&&meta.pubkey) {
+                    return Err(ProgramError::MissingRequiredSignature);
+                }
+            }
+            process_instruction(&mut accounts, instruction)?;
+        }
+        // The runtime purges accounts without any lamports left once the transaction is done
+        accounts.retain(|_, account| account.lamports > 0);
+        self.accounts = accounts;
+        Ok(())
+    }
+}
+
+thread_local! {
+    // The program under test
+    static PROGRAM_ID: RefCell<Pubkey> = RefCell::new(Pubkey::default());
+    // The program currently running, which is the one PDAs sign CPIs for
+    static CALLER: RefCell<Pubkey> = RefCell::new(Pubkey::default());
+    static CLOCK: RefCell<Clock> = RefCell::new(Clock::default());
+    // Where the accounts of the instruction currently running live in its input buffer
+    static SERIALIZED: RefCell<HashMap<Pubkey, SerializedAccount>> = RefCell::new(HashMap::new());
+}
+
+// Pointers into the input buffer, for the system program to allocate and assign accounts
+#[derive(Clone, Copy)]
+struct SerializedAccount {
+    owner: *mut Pubkey,
+    data_len: *mut u64,
+    data: *mut u8,
+}
+
+// The offsets of an account's fields in the input buffer, relative to its owner
+const LAMPORTS_OFFSET: usize = size_of::<Pubkey>();
+const DATA_LEN_OFFSET: usize = LAMPORTS_OFFSET + size_of::<u64>();
+const DATA_OFFSET: usize = DATA_LEN_OFFSET + size_of::<u64>();
+
+// Runs a top level instruction and writes the accounts it changed back into `accounts`
+fn process_instruction(accounts: &mut HashMap<Pubkey, Account>, instruction: &Instruction) -> ProgramResult {
+    let mut keys: Vec<(Pubkey, bool)> = Vec::new();
+    for meta in &instruction.accounts {
+        match keys.iter_mut().find(|(key, _)| *key == meta.pubkey) {
+            Some((_, is_writable)) => *is_writable |= meta.is_writable,
+            None => keys.push((meta.pubkey, meta.is_writable)),
+        }
+    }
+    // Accounts nobody has created yet are empty system accounts
+    let before: Vec<Account> = keys
+        .iter()
+        .map(|(key, _)| accounts.get(key).cloned().unwrap_or_default())
+        .collect();
+
+    let (mut buffer, owner_offsets) = serialize(instruction, &keys, &before);
+    let input = buffer.as_mut_ptr() as *mut u8;
+    SERIALIZED.with(|serialized| {
+        *serialized.borrow_mut() = keys
+            .iter()
+            .zip(&owner_offsets)
+            .map(|((key, _), offset)| {
+                // SAFETY: The offsets were computed while laying out the buffer
+                let serialized_account = unsafe {
+                    SerializedAccount {
+                        owner: input.add(*offset) as *mut Pubkey,
+                        data_len: input.add(offset + DATA_LEN_OFFSET) as *mut u64,
+                        data: input.add(offset + DATA_OFFSET),
+                    }
+                };
+                (*key, serialized_account)
+            })
+            .collect();
+    });
+
+    let result = {
+        // SAFETY: The buffer is laid out the way deserialize expects, and outlives the
+        // AccountInfos pointing into it
+        let (program_id, account_infos, data) = unsafe { entrypoint::deserialize(input) };
+        process(program_id, &account_infos, data)
+    };
+    SERIALIZED.with(|serialized| serialized.borrow_mut().clear());
+    result?;
+
+    // SAFETY: The AccountInfos are gone, so nothing points into the buffer anymore
+    let bytes = unsafe { std::slice::from_raw_parts(input as *const u8, buffer.len() * size_of::<u128>()) };
+    let read_u64 = |offset: usize| u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap());
+    let mut lamports_before = 0u128;
+    let mut lamports_after = 0u128;
+    for (((key, is_writable), offset), account_before) in keys.iter().zip(&owner_offsets).zip(before) {
+        let data_len = read_u64(offset + DATA_LEN_OFFSET) as usize;
+        let account = Account {
+            lamports: read_u64(offset + LAMPORTS_OFFSET),
+            data: bytes[offset + DATA_OFFSET..offset + DATA_OFFSET + data_len].to_vec(),
+            owner: Pubkey::new(&bytes[*offset..offset + LAMPORTS_OFFSET]),
+            executable: account_before.executable,
+        };
+        lamports_before += account_before.lamports as u128;
+        lamports_after += account.lamports as u128;
+        if !is_writable {
+            assert_eq!(account, account_before, "read-only account {} was modified", key);
+            continue;
+        }
+        accounts.insert(*key, account);
+    }
+    assert_eq!(lamports_before, lamports_after, "the instruction created or destroyed lamports");
+
+    Ok(())
+}
+
+// Lays out the instruction's accounts the way the BPF loader does, leaving room for every
+// account's data to grow by MAX_PERMITTED_DATA_INCREASE bytes. Returns the buffer along with
+// the offset of each (unique) account's owner in it.
+// NOTE The buffer is made of u128s, so the u64s in it are properly aligned.
+fn serialize(instruction: &Instruction, keys: &[(Pubkey, bool)], accounts: &[Account]) -> (Vec<u128>, Vec<usize>) {
+    let mut bytes = Vec::new();
+    let mut owner_offsets = Vec::new();
+    bytes.extend_from_slice(&(instruction.accounts.len() as u64).to_le_bytes());
+    for (index, meta) in instruction.accounts.iter().enumerate() {
+        // A duplicate only refers back to the first time the account was passed in
+        if let Some(first) = instruction.accounts[..index].iter().position(|other| other.pubkey == meta.pubkey) {
+            bytes.push(first as u8);
+            bytes.extend_from_slice(&[0; 7]);
+            continue;
+        }
+        let position = keys.iter().position(|(key, _)| *key == meta.pubkey).unwrap();
+        let is_signer = instruction.accounts.iter().any(|other| other.pubkey == meta.pubkey && other.is_signer);
+        let account = &accounts[position];
+        bytes.extend_from_slice(&[u8::MAX, is_signer as u8, keys[position].1 as u8, account.executable as u8]);
+        bytes.extend_from_slice(&[0; 4]);
+        bytes.extend_from_slice(meta.pubkey.as_ref());
+        owner_offsets.push(bytes.len());
+        bytes.extend_from_slice(account.owner.as_ref());
+        bytes.extend_from_slice(&account.lamports.to_le_bytes());
+        bytes.extend_from_slice(&(account.data.len() as u64).to_le_bytes());
+        bytes.extend_from_slice(&account.data);
+        bytes.resize(bytes.len() + MAX_PERMITTED_DATA_INCREASE, 0);
+        bytes.resize(bytes.len().next_multiple_of(size_of::<u128>()), 0);
+        // rent_epoch
+        bytes.extend_from_slice(&0u64.to_le_bytes());
+    }
+    bytes.extend_from_slice(&(instruction.data.len() as u64).to_le_bytes());
+    bytes.extend_from_slice(&instruction.data);
+    bytes.extend_from_slice(instruction.program_id.as_ref());
+
+    let mut buffer = vec![0u128; bytes.len().div_ceil(size_of::<u128>())];
+    // SAFETY: The buffer is at least as long as the bytes
+    unsafe {
+        std::ptr::copy_nonoverlapping(bytes.as_ptr(), buffer.as_mut_ptr() as *mut u8, bytes.len());
+    }
+    (buffer, owner_offsets)
+}
+
+// Runs an instruction of one of the programs we know, which becomes the caller of any
+// CPI it makes
+fn process(program_id: &Pubkey, accounts: &[AccountInfo], data: &[u8]) -> ProgramResult {
+    let caller = CALLER.with(|caller| caller.replace(*program_id));
+    let result = if *program_id == spl_token::id() {
+        spl_token::processor::Processor::process(program_id, accounts, data)
+    } else if *program_id == system_program::id() {
+        process_system_instruction(accounts, data)
+    } else if *program_id == PROGRAM_ID.with(|escrow_program_id| *escrow_program_id.borrow()) {
+        Processor::process(program_id, accounts, data)
+    } else {
+        Err(ProgramError::IncorrectProgramId)
+    };
+    CALLER.with(|current| *current.borrow_mut() = caller);
+    result
+}
+
+// The syscalls our program (and the token program) make
+struct Stubs;
+
+impl SyscallStubs for Stubs {
+    fn sol_log(&self, _message: &str) {}
+
+    // Checks the CPI the way the runtime does before running the callee: every account
+    // must have been passed in by the caller, and may only be signed (or written to) if it
+    // was for the caller, or is a PDA of the caller signing with its seeds.
+    fn sol_invoke_signed(
+        &self,
+        instruction: &Instruction,
+        account_infos: &[AccountInfo],
+        signers_seeds: &[&[&[u8]]],
+    ) -> ProgramResult {
+        let caller = CALLER.with(|caller| *caller.borrow());
+        let pda_signers = signers_seeds
+            .iter()
+            .map(|seeds| Pubkey::create_program_address(seeds, &caller))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| ProgramError::InvalidSeeds)?;
+
+        let mut accounts = Vec::with_capacity(instruction.accounts.len());
+        for meta in &instruction.accounts {
+            let mut account = account_infos
+                .iter()
+                .find(|account| *account.key == meta.pubkey)
+                .cloned()
+                .ok_or(ProgramError::NotEnoughAccountKeys)?;
+            if meta.is_signer && !account.is_signer && !pda_signers.contains(&meta.pubkey) {
+                return Err(ProgramError::MissingRequiredSignature);
+            }
+            if meta.is_writable && !account.is_writable {
+                return Err(ProgramError::InvalidArgument);
+            }
+            // NOTE An account passed in more than once has the privileges of all its metas
+            let metas = || instruction.accounts.iter().filter(|other| other.pubkey == meta.pubkey);
+            account.is_signer = metas().any(|other| other.is_signer);
+            account.is_writable = metas().any(|other| other.is_writable);
+            accounts.push(account);
+        }
+        if account_infos.iter().all(|account| *account.key != instruction.program_id) {
+            return Err(ProgramError::NotEnoughAccountKeys);
+        }
+
+        process(&instruction.program_id, &accounts, &instruction.data)
+    }
+
+    fn sol_get_clock_sysvar(&self, var_addr: *mut u8) -> u64 {
+        // SAFETY: Clock::get() passes in a Clock to fill in
+        CLOCK.with(|clock| unsafe { *(var_addr as *mut Clock) = clock.borrow().clone() });
+        entrypoint::SUCCESS
+    }
+
+    fn sol_get_rent_sysvar(&self, var_addr: *mut u8) -> u64 {
+        // SAFETY: Rent::get() passes in a Rent to fill in
+        unsafe { *(var_addr as *mut Rent) = Rent::default() };
+        entrypoint::SUCCESS
+    }
+}
+
+// The system program instructions our program uses
+fn process_system_instruction(accounts: &[AccountInfo], data: &[u8]) -> ProgramResult {
+    let account = |index: usize| accounts.get(index).ok_or(ProgramError::NotEnoughAccountKeys);
+    match bincode::deserialize(data).map_err(|_| ProgramError::InvalidInstructionData)? {
+        SystemInstruction::CreateAccount { lamports, space, owner } => {
+            let to = account(1)?;
+            // SystemError::AccountAlreadyInUse
+            if to.lamports() > 0 || !to.data_is_empty() {
+                return Err(ProgramError::Custom(0));
+            }
+            allocate(to, space)?;
+            assign(to, &owner)?;
+            transfer(account(0)?, to, lamports)
+        }
+        SystemInstruction::Assign { owner } => assign(account(0)?, &owner),
+        SystemInstruction::Transfer { lamports } => transfer(account(0)?, account(1)?, lamports),
+        SystemInstruction::Allocate { space } => allocate(account(0)?, space),
+        _ => Err(ProgramError::InvalidInstructionData),
+    }
+}
+
+fn transfer(from: &AccountInfo, to: &AccountInfo, lamports: u64) -> ProgramResult {
+    if !from.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+    if *from.owner != system_program::id() || !from.data_is_empty() {
+        return Err(ProgramError::InvalidArgument);
+    }
+    // SystemError::ResultWithNegativeLamports
+    let from_lamports = from.lamports().checked_sub(lamports).ok_or(ProgramError::Custom(1))?;
+    **from.try_borrow_mut_lamports()? = from_lamports;
+    **to.try_borrow_mut_lamports()? += lamports;
+    Ok(())
+}
+
+fn allocate(account: &AccountInfo, space: u64) -> ProgramResult {
+    if !account.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+    if *account.owner != system_program::id() || !account.data_is_empty() || space as usize > MAX_PERMITTED_DATA_INCREASE {
+        return Err(ProgramError::InvalidArgument);
+    }
+    let serialized = serialized(account.key)?;
+    let mut data = account.try_borrow_mut_data()?;
+    // SAFETY: The input buffer has MAX_PERMITTED_DATA_INCREASE (zeroed) bytes after the data
+    unsafe {
+        *serialized.data_len = space;
+        *data = std::slice::from_raw_parts_mut(serialized.data, space as usize);
+    }
+    Ok(())
+}
+
+fn assign(account: &AccountInfo, owner: &Pubkey) -> ProgramResult {
+    if !account.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+    if *account.owner != system_program::id() {
+        return Err(ProgramError::InvalidArgument);
+    }
+    // SAFETY: The AccountInfo's owner points to the same spot of the input buffer
+    unsafe { *serialized(account.key)?.owner = *owner };
+    Ok(())
+}
+
+fn serialized(key: &Pubkey) -> Result<SerializedAccount, ProgramError> {
+    SERIALIZED.with(|serialized| serialized.borrow().get(key).copied().ok_or(ProgramError::NotEnoughAccountKeys))
+}
@@ -0,0 +1,168 @@
+mod common;
+
+use solana_program::pubkey::Pubkey;
+
+use common::Bank;
+use solana_escrow::instruction::{self, NativeSide};
+
+// An escrow in which Alice (the initializer) offers 100 X tokens for 1_000 Y tokens, while
+// the protocol takes a fee of 1% (100 bps) of every payment
+struct Trade {
+    escrow: Pubkey,
+    vault: Pubkey,
+    initializer: Pubkey,
+    initializers_sending_token_account: Pubkey,
+    initializers_token_to_receive_account: Pubkey,
+    offered_mint: Pubkey,
+    requested_mint: Pubkey,
+    fee_recipient: Pubkey,
+}
+
+fn init_escrow(bank: &mut Bank) -> Trade {
+    let treasury = Pubkey::new_unique();
+    bank.init_config(100, &treasury);
+    let initializer = bank.create_wallet(1_000_000_000);
+    let offered_mint = bank.create_mint(6);
+    let requested_mint = bank.create_mint(9);
+    let initializers_sending_token_account = bank.create_token_account(&offered_mint, &initializer, 100);
+    let initializers_token_to_receive_account = bank.create_token_account(&requested_mint, &initializer, 0);
+    let fee_recipient = bank.create_token_account(&requested_mint, &treasury, 0);
+
+    let init_escrow_ix = instruction::init_escrow(
+        &bank.program_id,
+        &spl_token::id(),
+        &initializer,
+        &initializers_sending_token_account,
+        &initializers_token_to_receive_account,
+        &offered_mint,
+        &requested_mint,
+        1_000,
+        100,
+        None,
+        NativeSide::None,
+        0,
+        None,
+        None,
+        &[],
+        &[],
+        false,
+        None,
+    )
+    .unwrap();
+    bank.process_transaction(&[init_escrow_ix], &[&initializer]).unwrap();
+
+    let (escrow, _) = instruction::find_escrow_address(&bank.program_id, &initializer, 0);
+    let (vault, _) = instruction::find_vault_address(&bank.program_id, &escrow);
+    assert_eq!(bank.token_balance(&vault), 100);
+    assert_eq!(bank.token_balance(&initializers_sending_token_account), 0);
+
+    Trade {
+        escrow,
+        vault,
+        initializer,
+        initializers_sending_token_account,
+        initializers_token_to_receive_account,
+        offered_mint,
+        requested_mint,
+        fee_recipient,
+    }
+}
+
+// Bob (the taker) with enough Y tokens to take the whole escrow
+struct Taker {
+    taker: Pubkey,
+    takers_sending_token_account: Pubkey,
+    takers_receiving_token_account: Pubkey,
+}
+
+fn taker(bank: &mut Bank, trade: &Trade) -> Taker {
+    let taker = bank.create_wallet(1_000_000_000);
+    Taker {
+        taker,
+        takers_sending_token_account: bank.create_token_account(&trade.requested_mint, &taker, 1_000),
+        takers_receiving_token_account: bank.create_token_account(&trade.offered_mint, &taker, 0),
+    }
+}
+
+fn exchange(bank: &mut Bank, trade: &Trade, taker: &Taker, amount: u64) {
+    let exchange_ix = instruction::exchange(
+        &bank.program_id,
+        &spl_token::id(),
+        &spl_token::id(),
+        &taker.taker,
+        &taker.takers_sending_token_account,
+        &taker.takers_receiving_token_account,
+        &trade.initializer,
+        &trade.initializers_token_to_receive_account,
+        &trade.escrow,
+        &trade.vault,
+        &trade.fee_recipient,
+        Some(&trade.offered_mint),
+        Some(&trade.requested_mint),
+        amount,
+        vec![],
+        &[],
+        &[],
+        false,
+    )
+    .unwrap();
+    bank.process_transaction(&[exchange_ix], &[&taker.taker]).unwrap();
+}
+
+#[test]
+fn test_partial_fill_then_final_fill() {
+    let mut bank = Bank::new();
+    let trade = init_escrow(&mut bank);
+    let taker = taker(&mut bank, &trade);
+
+    // Bob takes 30 of the 100 X tokens, paying 300 Y tokens (3 of which are the fee)
+    exchange(&mut bank, &trade, &taker, 30);
+    assert_eq!(bank.token_balance(&taker.takers_receiving_token_account), 30);
+    assert_eq!(bank.token_balance(&taker.takers_sending_token_account), 700);
+    assert_eq!(bank.token_balance(&trade.initializers_token_to_receive_account), 297);
+    assert_eq!(bank.token_balance(&trade.fee_recipient), 3);
+    assert_eq!(bank.token_balance(&trade.vault), 70);
+    assert_eq!(bank.escrow(&trade.escrow).remaining_amount, 70);
+
+    // Then he takes the rest, which closes the escrow
+    let initializer_lamports = bank.lamports(&trade.initializer) + bank.lamports(&trade.escrow) + bank.lamports(&trade.vault);
+    exchange(&mut bank, &trade, &taker, 70);
+    assert_eq!(bank.token_balance(&taker.takers_receiving_token_account), 100);
+    assert_eq!(bank.token_balance(&taker.takers_sending_token_account), 0);
+    assert_eq!(bank.token_balance(&trade.initializers_token_to_receive_account), 990);
+    assert_eq!(bank.token_balance(&trade.fee_recipient), 10);
+    assert!(bank.account(&trade.vault).is_none());
+    assert!(bank.account(&trade.escrow).is_none());
+    assert_eq!(bank.lamports(&trade.initializer), initializer_lamports);
+}
+
+#[test]
+fn test_cancel_after_partial_fill() {
+    let mut bank = Bank::new();
+    let trade = init_escrow(&mut bank);
+    let taker = taker(&mut bank, &trade);
+    exchange(&mut bank, &trade, &taker, 30);
+
+    // Alice gets back the 70 X tokens nobody took, plus the rent of the escrow and its vault
+    let initializer_lamports = bank.lamports(&trade.initializer) + bank.lamports(&trade.escrow) + bank.lamports(&trade.vault);
+    let cancel_ix = instruction::cancel(
+        &bank.program_id,
+        &spl_token::id(),
+        &trade.initializer,
+        &trade.initializers_sending_token_account,
+        &trade.escrow,
+        &trade.vault,
+        Some(&trade.offered_mint),
+        &[],
+        false,
+    )
+    .unwrap();
+    bank.process_transaction(&[cancel_ix], &[&trade.initializer]).unwrap();
+
+    assert_eq!(bank.token_balance(&trade.initializers_sending_token_account), 70);
+    assert_eq!(bank.token_balance(&trade.initializers_token_to_receive_account), 297);
+    assert_eq!(bank.token_balance(&taker.takers_receiving_token_account), 30);
+    assert!(bank.account(&trade.vault).is_none());
+    assert!(bank.account(&trade.escrow).is_none());
+    assert_eq!(bank.lamports(&trade.initializer), initializer_lamports);
+}
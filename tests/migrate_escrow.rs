@@ -0,0 +1,209 @@
+mod common;
+
+use borsh::BorshSerialize;
+use solana_program::{program_error::ProgramError, program_pack::Pack, pubkey::Pubkey, system_program};
+
+use common::{Account, Bank};
+use solana_escrow::{
+    error::EscrowError,
+    instruction,
+    state::{AccountType, AssetKind, Escrow, LegacyEscrow, TokenProgram},
+};
+
+// A legacy escrow the way the original program left it: a (non-PDA) escrow account that's
+// LegacyEscrow::LEN bytes long, and a temp token account owned by the single PDA `[b"escrow"]`
+struct Legacy {
+    escrow: Pubkey,
+    vault: Pubkey,
+    initializer: Pubkey,
+    initializers_token_to_receive_account: Pubkey,
+    offered_mint: Pubkey,
+    requested_mint: Pubkey,
+}
+
+fn legacy_escrow(bank: &mut Bank) -> Legacy {
+    let initializer = bank.create_wallet(1_000_000_000);
+    let offered_mint = bank.create_mint(6);
+    let requested_mint = bank.create_mint(9);
+    let (legacy_pda, _) = instruction::find_legacy_escrow_authority(&bank.program_id);
+    let vault = bank.create_token_account(&offered_mint, &legacy_pda, 100);
+    let initializers_token_to_receive_account = bank.create_token_account(&requested_mint, &initializer, 0);
+
+    let escrow = Pubkey::new_unique();
+    let data = LegacyEscrow {
+        is_initialized: true,
+        initializer_pubkey: initializer,
+        temp_token_account_pubkey: vault,
+        initializer_token_to_receive_account_pubkey: initializers_token_to_receive_account,
+        expected_amount: 5_000,
+    }
+    .try_to_vec()
+    .unwrap();
+    let lamports = bank.rent().minimum_balance(LegacyEscrow::LEN);
+    bank.set_account(&escrow, Account::new(lamports, data, &bank.program_id));
+
+    Legacy {
+        escrow,
+        vault,
+        initializer,
+        initializers_token_to_receive_account,
+        offered_mint,
+        requested_mint,
+    }
+}
+
+fn migrate(bank: &mut Bank, legacy: &Legacy, payer: &Pubkey) -> Result<(), ProgramError> {
+    let migrate_ix = instruction::migrate_escrow(
+        &bank.program_id,
+        payer,
+        &legacy.escrow,
+        &legacy.vault,
+        &legacy.initializers_token_to_receive_account,
+        &legacy.offered_mint,
+        &legacy.requested_mint,
+    )
+    .unwrap();
+    bank.process_transaction(&[migrate_ix], &[payer])
+}
+
+#[test]
+fn test_migrate_legacy_escrow() {
+    let mut bank = Bank::new();
+    let legacy = legacy_escrow(&mut bank);
+    let payer = bank.create_wallet(1_000_000_000);
+    let escrow_lamports = bank.lamports(&legacy.escrow);
+
+    migrate(&mut bank, &legacy, &payer).unwrap();
+
+    // The account was grown in place and the payer topped up its rent
+    let escrow_account = bank.account(&legacy.escrow).unwrap();
+    assert_eq!(escrow_account.data.len(), Escrow::LEN);
+    assert_eq!(&escrow_account.data[..2], &[AccountType::Escrow as u8, Escrow::VERSION]);
+    assert_eq!(escrow_account.owner, bank.program_id);
+    let rent_exempt_lamports = bank.rent().minimum_balance(Escrow::LEN);
+    assert_eq!(escrow_account.lamports, rent_exempt_lamports);
+    assert_eq!(bank.lamports(&payer), 1_000_000_000 - (rent_exempt_lamports - escrow_lamports));
+
+    let escrow = bank.escrow(&legacy.escrow);
+    let (pda, bump_seed) = instruction::find_escrow_authority(&bank.program_id, &legacy.escrow);
+    assert!(escrow.is_initialized);
+    assert_eq!(escrow.initializer_pubkey, legacy.initializer);
+    assert_eq!(escrow.temp_token_account_pubkey, legacy.vault);
+    assert_eq!(escrow.initializer_token_to_receive_account_pubkey, legacy.initializers_token_to_receive_account);
+    assert_eq!(escrow.expected_amount, 5_000);
+    assert_eq!(escrow.bump_seed, bump_seed);
+    assert_eq!(escrow.expires_at, None);
+    assert_eq!((escrow.offered_amount, escrow.remaining_amount), (100, 100));
+    assert_eq!((escrow.offered_mint, escrow.requested_mint), (legacy.offered_mint, legacy.requested_mint));
+    assert_eq!((escrow.offered_kind, escrow.requested_kind), (AssetKind::Token, AssetKind::Token));
+    assert_eq!(escrow.offered_token_program, TokenProgram::SplToken);
+    assert_eq!((escrow.offered_decimals, escrow.requested_decimals), (6, 9));
+
+    // The tokens stayed in the vault, which now belongs to the escrow's own PDA
+    let vault = bank.unpack::<spl_token::state::Account>(&legacy.vault);
+    assert_eq!(vault.owner, pda);
+    assert_eq!(vault.amount, 100);
+}
+
+#[test]
+fn test_exchange_migrated_escrow() {
+    let mut bank = Bank::new();
+    let treasury = Pubkey::new_unique();
+    bank.init_config(100, &treasury);
+    let legacy = legacy_escrow(&mut bank);
+    let payer = bank.create_wallet(1_000_000_000);
+    migrate(&mut bank, &legacy, &payer).unwrap();
+
+    let taker = bank.create_wallet(1_000_000_000);
+    let takers_sending_token_account = bank.create_token_account(&legacy.requested_mint, &taker, 5_000);
+    let takers_receiving_token_account = bank.create_token_account(&legacy.offered_mint, &taker, 0);
+    let fee_recipient = bank.create_token_account(&legacy.requested_mint, &treasury, 0);
+    let initializer_lamports = bank.lamports(&legacy.initializer)
+        + bank.lamports(&legacy.escrow)
+        + bank.lamports(&legacy.vault);
+
+    // NOTE The vault is the legacy temp token account saved in the escrow, NOT the
+    // escrow's vault PDA
+    let exchange_ix = instruction::exchange(
+        &bank.program_id,
+        &spl_token::id(),
+        &spl_token::id(),
+        &taker,
+        &takers_sending_token_account,
+        &takers_receiving_token_account,
+        &legacy.initializer,
+        &legacy.initializers_token_to_receive_account,
+        &legacy.escrow,
+        &bank.escrow(&legacy.escrow).temp_token_account_pubkey,
+        &fee_recipient,
+        Some(&legacy.offered_mint),
+        Some(&legacy.requested_mint),
+        100,
+        vec![],
+        &[],
+        &[],
+        false,
+    )
+    .unwrap();
+    bank.process_transaction(&[exchange_ix], &[&taker]).unwrap();
+
+    assert_eq!(bank.token_balance(&takers_receiving_token_account), 100);
+    assert_eq!(bank.token_balance(&takers_sending_token_account), 0);
+    assert_eq!(bank.token_balance(&legacy.initializers_token_to_receive_account), 4_950);
+    assert_eq!(bank.token_balance(&fee_recipient), 50);
+    // Both the vault and the escrow account are closed, and their rent went to the initializer
+    assert!(bank.account(&legacy.vault).is_none());
+    assert!(bank.account(&legacy.escrow).is_none());
+    assert_eq!(bank.lamports(&legacy.initializer), initializer_lamports);
+}
+
+#[test]
+fn test_migrate_escrow_twice() {
+    let mut bank = Bank::new();
+    let legacy = legacy_escrow(&mut bank);
+    let payer = bank.create_wallet(1_000_000_000);
+    migrate(&mut bank, &legacy, &payer).unwrap();
+
+    assert_eq!(
+        migrate(&mut bank, &legacy, &payer),
+        Err(EscrowError::EscrowAlreadyMigrated.into())
+    );
+    assert_eq!(bank.account(&legacy.escrow).unwrap().data.len(), Escrow::LEN);
+}
+
+#[test]
+fn test_migrate_escrow_without_enough_lamports_for_rent() {
+    let mut bank = Bank::new();
+    let legacy = legacy_escrow(&mut bank);
+    // The payer can't cover the rent of the bigger account, so nothing changes
+    let payer = bank.create_wallet(1);
+
+    assert!(migrate(&mut bank, &legacy, &payer).is_err());
+    let escrow_account = bank.account(&legacy.escrow).unwrap();
+    assert_eq!(escrow_account.data.len(), LegacyEscrow::LEN);
+    assert_eq!(bank.lamports(&payer), 1);
+    assert_eq!(bank.account(&payer).unwrap().owner, system_program::id());
+}
+
+#[test]
+fn test_legacy_escrow_needs_migration() {
+    let mut bank = Bank::new();
+    let legacy = legacy_escrow(&mut bank);
+    let cancel_ix = instruction::cancel(
+        &bank.program_id,
+        &spl_token::id(),
+        &legacy.initializer,
+        &Pubkey::new_unique(),
+        &legacy.escrow,
+        &legacy.vault,
+        Some(&legacy.offered_mint),
+        &[],
+        false,
+    )
+    .unwrap();
+
+    assert_eq!(
+        bank.process_transaction(&[cancel_ix], &[&legacy.initializer]),
+        Err(EscrowError::EscrowNeedsMigration.into())
+    );
+}